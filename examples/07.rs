@@ -1,14 +1,17 @@
 use std::{
     collections::{HashMap, HashSet, VecDeque},
     fmt::Debug,
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU8, Ordering},
-        Arc, Condvar, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Condvar, Mutex, MutexGuard, RwLock,
     },
-    thread,
-    time::Duration,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 #[derive(Clone, Debug)]
@@ -24,6 +27,10 @@ enum LogType {
     Abort(AbortLog),
     Insert(InsertLog),
     CompensateInsert(CompensateInsertLog),
+    BeginCheckpoint,
+    EndCheckpoint(EndCheckpointLog),
+    Delete(DeleteLog),
+    CompensateDelete(CompensateDeleteLog),
 }
 
 #[derive(Clone, Debug)]
@@ -34,6 +41,8 @@ struct BeginLog {
 #[derive(Clone, Debug)]
 struct CommitLog {
     transaction_id: u8,
+    // milliseconds since the unix epoch
+    timestamp: u64,
 }
 
 #[derive(Clone, Debug)]
@@ -58,173 +67,815 @@ struct CompensateInsertLog {
     slot_id: u8,
 }
 
+#[derive(Clone, Debug)]
+struct DeleteLog {
+    prev_lsn: u8,
+    transaction_id: u8,
+    page_id: u8,
+    slot_id: u8,
+}
+
+#[derive(Clone, Debug)]
+struct CompensateDeleteLog {
+    next_compenstate_lsn: u8,
+    transaction_id: u8,
+    page_id: u8,
+    slot_id: u8,
+}
+
+#[derive(Clone, Debug)]
+struct EndCheckpointLog {
+    begin_checkpoint_lsn: u8,
+    max_transaction_id: u8,
+    transaction_table: Vec<(u8, TransactionTableEntry)>,
+    // page_id -> rec_lsn
+    dirty_page_table: Vec<(u8, u8)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TransactionTableEntry {
+    first_lsn: u8,
+    last_lsn: u8,
+}
+
 const BEGIN_LOG_TYPE: u8 = 0;
 const COMMIT_LOG_TYPE: u8 = 1;
 const ABORT_LOG_TYPE: u8 = 2;
 const INSERT_LOG_TYPE: u8 = 3;
 const COMPENSATE_INSERT_LOG_TYPE: u8 = 4;
+const BEGIN_CHECKPOINT_LOG_TYPE: u8 = 5;
+const END_CHECKPOINT_LOG_TYPE: u8 = 6;
+const DELETE_LOG_TYPE: u8 = 7;
+const COMPENSATE_DELETE_LOG_TYPE: u8 = 8;
+
+impl LogType {
+    fn transaction_id(&self) -> Option<u8> {
+        match self {
+            LogType::Begin(log) => Some(log.transaction_id),
+            LogType::Commit(log) => Some(log.transaction_id),
+            LogType::Abort(log) => Some(log.transaction_id),
+            LogType::Insert(log) => Some(log.transaction_id),
+            LogType::CompensateInsert(log) => Some(log.transaction_id),
+            LogType::Delete(log) => Some(log.transaction_id),
+            LogType::CompensateDelete(log) => Some(log.transaction_id),
+            LogType::BeginCheckpoint | LogType::EndCheckpoint(_) => None,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self {
+            LogType::Begin(_) => "Begin",
+            LogType::Commit(_) => "Commit",
+            LogType::Abort(_) => "Abort",
+            LogType::Insert(_) => "Insert",
+            LogType::CompensateInsert(_) => "CompensateInsert",
+            LogType::BeginCheckpoint => "BeginCheckpoint",
+            LogType::EndCheckpoint(_) => "EndCheckpoint",
+            LogType::Delete(_) => "Delete",
+            LogType::CompensateDelete(_) => "CompensateDelete",
+        }
+    }
+    fn page_id(&self) -> Option<u8> {
+        match self {
+            LogType::Insert(log) => Some(log.page_id),
+            LogType::CompensateInsert(log) => Some(log.page_id),
+            LogType::Delete(log) => Some(log.page_id),
+            LogType::CompensateDelete(log) => Some(log.page_id),
+            _ => None,
+        }
+    }
+}
 
 impl Log {
+    // length(1) + crc32(4), followed by the record itself.
+    const HEADER_SIZE: usize = 5;
+
     fn serialize(&self) -> Vec<u8> {
-        let mut bytes = vec![self.lsn];
+        self.try_serialize()
+            .expect("log record is longer than 255 bytes")
+    }
+    // None if the record does not fit its u8 length fields.
+    fn try_serialize(&self) -> Option<Vec<u8>> {
+        let mut body = vec![self.lsn];
         match self.log_type {
             LogType::Begin(ref commit_log) => {
-                bytes.push(BEGIN_LOG_TYPE);
-                bytes.push(commit_log.transaction_id);
+                body.push(BEGIN_LOG_TYPE);
+                body.push(commit_log.transaction_id);
             }
             LogType::Commit(ref commit_log) => {
-                bytes.push(COMMIT_LOG_TYPE);
-                bytes.push(commit_log.transaction_id);
+                body.push(COMMIT_LOG_TYPE);
+                body.push(commit_log.transaction_id);
+                body.extend(commit_log.timestamp.to_le_bytes());
             }
             LogType::Abort(ref abort_log) => {
-                bytes.push(ABORT_LOG_TYPE);
-                bytes.push(abort_log.transaction_id);
+                body.push(ABORT_LOG_TYPE);
+                body.push(abort_log.transaction_id);
             }
             LogType::Insert(ref insert_log) => {
-                bytes.push(INSERT_LOG_TYPE);
-                bytes.push(insert_log.prev_lsn);
-                bytes.push(insert_log.transaction_id);
-                bytes.push(insert_log.page_id);
-                bytes.push(insert_log.slot_id);
-                bytes.push(insert_log.tuple);
+                body.push(INSERT_LOG_TYPE);
+                body.push(insert_log.prev_lsn);
+                body.push(insert_log.transaction_id);
+                body.push(insert_log.page_id);
+                body.push(insert_log.slot_id);
+                body.push(insert_log.tuple);
             }
             LogType::CompensateInsert(ref compensate_insert_log) => {
-                bytes.push(COMPENSATE_INSERT_LOG_TYPE);
-                bytes.push(compensate_insert_log.next_compenstate_lsn);
-                bytes.push(compensate_insert_log.transaction_id);
-                bytes.push(compensate_insert_log.page_id);
-                bytes.push(compensate_insert_log.slot_id);
-            }
-        }
-        bytes
-    }
-    fn deserialize(bytes: &[u8]) -> (Self, usize) {
-        let lsn = bytes[0];
-        let (log_type, log_size) = match bytes[1] {
-            BEGIN_LOG_TYPE => {
-                let transaction_id = bytes[2];
-                (LogType::Begin(BeginLog { transaction_id }), 3)
-            }
-            COMMIT_LOG_TYPE => {
-                let transaction_id = bytes[2];
-                (LogType::Commit(CommitLog { transaction_id }), 3)
-            }
-            ABORT_LOG_TYPE => {
-                let transaction_id = bytes[2];
-                (LogType::Abort(AbortLog { transaction_id }), 3)
-            }
-            INSERT_LOG_TYPE => {
-                let prev_lsn = bytes[2];
-                let transaction_id = bytes[3];
-                let page_id = bytes[4];
-                let slot_id = bytes[5];
-                let tuple = bytes[6];
-                (
-                    LogType::Insert(InsertLog {
-                        prev_lsn,
-                        transaction_id,
-                        page_id,
-                        slot_id,
-                        tuple,
-                    }),
-                    7,
-                )
+                body.push(COMPENSATE_INSERT_LOG_TYPE);
+                body.push(compensate_insert_log.next_compenstate_lsn);
+                body.push(compensate_insert_log.transaction_id);
+                body.push(compensate_insert_log.page_id);
+                body.push(compensate_insert_log.slot_id);
             }
-            COMPENSATE_INSERT_LOG_TYPE => {
-                let next_compenstate_lsn = bytes[2];
-                let transaction_id = bytes[3];
-                let page_id = bytes[4];
-                let slot_id = bytes[5];
-                (
-                    LogType::CompensateInsert(CompensateInsertLog {
-                        next_compenstate_lsn,
-                        transaction_id,
-                        page_id,
-                        slot_id,
-                    }),
-                    6,
-                )
+            LogType::BeginCheckpoint => {
+                body.push(BEGIN_CHECKPOINT_LOG_TYPE);
+            }
+            LogType::EndCheckpoint(ref end_checkpoint_log) => {
+                body.push(END_CHECKPOINT_LOG_TYPE);
+                body.push(end_checkpoint_log.begin_checkpoint_lsn);
+                body.push(end_checkpoint_log.max_transaction_id);
+                body.push(u8::try_from(end_checkpoint_log.transaction_table.len()).ok()?);
+                for (transaction_id, entry) in &end_checkpoint_log.transaction_table {
+                    body.extend([*transaction_id, entry.first_lsn, entry.last_lsn]);
+                }
+                body.push(u8::try_from(end_checkpoint_log.dirty_page_table.len()).ok()?);
+                for (page_id, rec_lsn) in &end_checkpoint_log.dirty_page_table {
+                    body.extend([*page_id, *rec_lsn]);
+                }
+            }
+            LogType::Delete(ref delete_log) => {
+                body.push(DELETE_LOG_TYPE);
+                body.push(delete_log.prev_lsn);
+                body.push(delete_log.transaction_id);
+                body.push(delete_log.page_id);
+                body.push(delete_log.slot_id);
+            }
+            LogType::CompensateDelete(ref compensate_delete_log) => {
+                body.push(COMPENSATE_DELETE_LOG_TYPE);
+                body.push(compensate_delete_log.next_compenstate_lsn);
+                body.push(compensate_delete_log.transaction_id);
+                body.push(compensate_delete_log.page_id);
+                body.push(compensate_delete_log.slot_id);
+            }
+        }
+        let mut bytes = vec![u8::try_from(body.len()).ok()?];
+        bytes.extend(crc32(&body).to_le_bytes());
+        bytes.extend(body);
+        Some(bytes)
+    }
+    // Reads one framed record from a stream, e.g. a replication connection.
+    fn read_from(reader: &mut impl Read) -> Option<Self> {
+        let mut bytes = vec![0; Self::HEADER_SIZE];
+        reader.read_exact(&mut bytes).ok()?;
+        bytes.resize(Self::HEADER_SIZE + bytes[0] as usize, 0);
+        reader.read_exact(&mut bytes[Self::HEADER_SIZE..]).ok()?;
+        Some(Log::deserialize(&bytes)?.0)
+    }
+    // Returns None for a torn or corrupt record.
+    fn deserialize(bytes: &[u8]) -> Option<(Self, usize)> {
+        if bytes.len() < Self::HEADER_SIZE {
+            return None;
+        }
+        let log_size = Self::HEADER_SIZE + bytes[0] as usize;
+        let crc = u32::from_le_bytes(bytes[1..Self::HEADER_SIZE].try_into().unwrap());
+        let body = bytes.get(Self::HEADER_SIZE..log_size)?;
+        if crc32(body) != crc {
+            return None;
+        }
+        let log = Self::deserialize_body(body)?;
+        Some((log, log_size))
+    }
+    fn deserialize_body(bytes: &[u8]) -> Option<Self> {
+        let (&lsn, bytes) = bytes.split_first()?;
+        let (&log_type, bytes) = bytes.split_first()?;
+        let log_type = match (log_type, bytes) {
+            (BEGIN_LOG_TYPE, &[transaction_id]) => LogType::Begin(BeginLog { transaction_id }),
+            (COMMIT_LOG_TYPE, &[transaction_id, ref timestamp @ ..]) if timestamp.len() == 8 => {
+                LogType::Commit(CommitLog {
+                    transaction_id,
+                    timestamp: u64::from_le_bytes(timestamp.try_into().unwrap()),
+                })
+            }
+            (ABORT_LOG_TYPE, &[transaction_id]) => LogType::Abort(AbortLog { transaction_id }),
+            (INSERT_LOG_TYPE, &[prev_lsn, transaction_id, page_id, slot_id, tuple]) => {
+                LogType::Insert(InsertLog {
+                    prev_lsn,
+                    transaction_id,
+                    page_id,
+                    slot_id,
+                    tuple,
+                })
+            }
+            (
+                COMPENSATE_INSERT_LOG_TYPE,
+                &[next_compenstate_lsn, transaction_id, page_id, slot_id],
+            ) => LogType::CompensateInsert(CompensateInsertLog {
+                next_compenstate_lsn,
+                transaction_id,
+                page_id,
+                slot_id,
+            }),
+            (BEGIN_CHECKPOINT_LOG_TYPE, &[]) => LogType::BeginCheckpoint,
+            (END_CHECKPOINT_LOG_TYPE, bytes) => {
+                LogType::EndCheckpoint(Self::deserialize_end_checkpoint(bytes)?)
+            }
+            (DELETE_LOG_TYPE, &[prev_lsn, transaction_id, page_id, slot_id]) => {
+                LogType::Delete(DeleteLog {
+                    prev_lsn,
+                    transaction_id,
+                    page_id,
+                    slot_id,
+                })
             }
-            _ => panic!("Unknown log type"),
+            (
+                COMPENSATE_DELETE_LOG_TYPE,
+                &[next_compenstate_lsn, transaction_id, page_id, slot_id],
+            ) => LogType::CompensateDelete(CompensateDeleteLog {
+                next_compenstate_lsn,
+                transaction_id,
+                page_id,
+                slot_id,
+            }),
+            _ => return None,
         };
-        (Self { lsn, log_type }, log_size)
+        Some(Self { lsn, log_type })
+    }
+    fn deserialize_end_checkpoint(bytes: &[u8]) -> Option<EndCheckpointLog> {
+        let (&[begin_checkpoint_lsn, max_transaction_id, transaction_table_length], bytes) =
+            bytes.split_first_chunk::<3>()?;
+        let (transaction_table, bytes) =
+            bytes.split_at_checked(transaction_table_length as usize * 3)?;
+        let (&dirty_page_table_length, dirty_page_table) = bytes.split_first()?;
+        if dirty_page_table.len() != dirty_page_table_length as usize * 2 {
+            return None;
+        }
+        Some(EndCheckpointLog {
+            begin_checkpoint_lsn,
+            max_transaction_id,
+            transaction_table: transaction_table
+                .chunks(3)
+                .map(|entry| {
+                    (
+                        entry[0],
+                        TransactionTableEntry {
+                            first_lsn: entry[1],
+                            last_lsn: entry[2],
+                        },
+                    )
+                })
+                .collect(),
+            dirty_page_table: dirty_page_table
+                .chunks(2)
+                .map(|entry| (entry[0], entry[1]))
+                .collect(),
+        })
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
     }
+    !crc
 }
 
+const DEFAULT_LOG_SEGMENT_SIZE: usize = 128;
+
 struct LogManager {
-    file: File,
-    current_lsn: u8,
+    dir_name: String,
+    // The lsn the next record gets, None once every lsn has been used.
+    current_lsn: Option<u8>,
     buffer: Vec<Log>,
+    group_commit: Arc<GroupCommit>,
+    // tx_id -> first/last lsn of every transaction without commit or abort
+    transaction_table: HashMap<u8, TransactionTableEntry>,
+    max_transaction_id: u8,
+    // The lsn of the last EndCheckpoint record, if nothing came after it.
+    last_checkpoint_lsn: Option<u8>,
+    // Start lsns of the running base backups. Keeps truncation from removing
+    // segments they still copy.
+    backup_start_lsns: Vec<u8>,
+    sync_replication: Arc<SyncReplication>,
 }
 
 impl LogManager {
-    fn init(file_name: &str) -> Self {
+    fn init(dir_name: &str) -> Self {
+        LogManager::create(dir_name, &[]);
+        LogManager::load(dir_name)
+    }
+    // Replaces the log in dir_name with one holding exactly these records.
+    // Other files in dir_name, e.g. logical replication slots, are kept.
+    fn create(dir_name: &str, logs: &[Log]) {
+        let path = Path::new(dir_name);
+        if path.is_dir() {
+            for segment_lsn in LogManager::segment_lsns(dir_name) {
+                fs::remove_file(LogManager::segment_path(dir_name, segment_lsn)).unwrap();
+            }
+        } else if path.exists() {
+            fs::remove_file(path).unwrap();
+        }
+        fs::create_dir_all(path).unwrap();
+        SegmentWriter::open(dir_name, logs.first().map_or(0, |log| log.lsn)).write(logs);
+    }
+    fn load(dir_name: &str) -> Self {
+        let logs = LogManager::read_segments(dir_name);
+        let last_segment_lsn = *LogManager::segment_lsns(dir_name).last().unwrap_or(&0);
         Self {
-            file: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(file_name)
-                .unwrap(),
-            current_lsn: 0,
+            dir_name: dir_name.to_string(),
+            group_commit: Arc::new(GroupCommit::new(
+                SegmentWriter::open(dir_name, last_segment_lsn),
+                logs.last().map(|log| log.lsn),
+            )),
+            current_lsn: logs.last().map_or(Some(0), |log| log.lsn.checked_add(1)),
             buffer: Vec::new(),
+            transaction_table: HashMap::new(),
+            max_transaction_id: 0,
+            last_checkpoint_lsn: logs
+                .last()
+                .filter(|log| matches!(log.log_type, LogType::EndCheckpoint(_)))
+                .map(|log| log.lsn),
+            backup_start_lsns: Vec::new(),
+            sync_replication: Arc::new(SyncReplication::new()),
         }
     }
-    fn load(file_name: &str) -> Self {
-        let mut manager = Self {
-            file: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(file_name)
-                .unwrap(),
-            current_lsn: 0,
-            buffer: Vec::new(),
-        };
-        let logs = manager.read();
-        let current_lsn = logs.last().map_or(0, |log| log.lsn);
-        manager.current_lsn = current_lsn;
-        manager
+    fn segment_path(dir_name: &str, start_lsn: u8) -> PathBuf {
+        Path::new(dir_name).join(format!("{:03}.log", start_lsn))
+    }
+    fn segment_lsns(dir_name: &str) -> Vec<u8> {
+        let mut segment_lsns: Vec<u8> = fs::read_dir(dir_name)
+            .unwrap()
+            .filter_map(|entry| {
+                let file_name = entry.unwrap().file_name();
+                file_name.to_str()?.strip_suffix(".log")?.parse().ok()
+            })
+            .collect();
+        segment_lsns.sort();
+        segment_lsns
     }
     fn read(&mut self) -> Vec<Log> {
-        self.file.seek(SeekFrom::Start(0)).unwrap();
-        let mut bytes = Vec::new();
-        self.file.read_to_end(&mut bytes).unwrap();
-        let mut logs = Vec::new();
-        loop {
-            if bytes.is_empty() {
-                break;
+        LogManager::read_segments(&self.dir_name)
+    }
+    fn read_segments(dir_name: &str) -> Vec<Log> {
+        let mut reader = LogReader::open(dir_name);
+        let logs: Vec<Log> = reader.by_ref().collect();
+        if let Some((segment_lsn, offset)) = reader.torn_at {
+            // Drop the torn or corrupt tail so new appends follow the last valid record.
+            let file = OpenOptions::new()
+                .write(true)
+                .open(LogManager::segment_path(dir_name, segment_lsn))
+                .unwrap();
+            file.set_len(offset as u64).unwrap();
+            file.sync_all().unwrap();
+            for later_segment_lsn in LogManager::segment_lsns(dir_name)
+                .into_iter()
+                .filter(|lsn| *lsn > segment_lsn)
+            {
+                fs::remove_file(LogManager::segment_path(dir_name, later_segment_lsn)).unwrap();
             }
-            let (log, log_size) = Log::deserialize(&bytes);
-            logs.push(log);
-            bytes = bytes.split_off(log_size);
         }
         logs
     }
+    fn size(&self) -> u64 {
+        LogManager::segment_lsns(&self.dir_name)
+            .into_iter()
+            .map(|lsn| {
+                fs::metadata(LogManager::segment_path(&self.dir_name, lsn))
+                    .unwrap()
+                    .len()
+            })
+            .sum()
+    }
     fn append(&mut self, log_type: LogType) -> Log {
         let log = Log {
-            lsn: self.current_lsn,
+            lsn: self
+                .current_lsn
+                .expect("log sequence numbers are exhausted"),
             log_type,
         };
-        self.current_lsn += 1;
+        self.current_lsn = log.lsn.checked_add(1);
+        self.last_checkpoint_lsn = match log.log_type {
+            LogType::EndCheckpoint(_) => Some(log.lsn),
+            _ => None,
+        };
+        if let Some(transaction_id) = log.log_type.transaction_id() {
+            self.max_transaction_id = self.max_transaction_id.max(transaction_id);
+            match log.log_type {
+                LogType::Commit(_) | LogType::Abort(_) => {
+                    self.transaction_table.remove(&transaction_id);
+                }
+                _ => {
+                    self.transaction_table
+                        .entry(transaction_id)
+                        .or_insert(TransactionTableEntry {
+                            first_lsn: log.lsn,
+                            last_lsn: log.lsn,
+                        })
+                        .last_lsn = log.lsn;
+                }
+            }
+        }
         self.buffer.push(log.clone());
         log.clone()
     }
-    fn flush(&mut self) {
-        self.file.seek(SeekFrom::End(0)).unwrap();
-        let bytes: Vec<u8> = self.buffer.iter().flat_map(|log| log.serialize()).collect();
-        self.file.write_all(&bytes).unwrap();
+    fn remaining_lsns(&self) -> usize {
+        self.current_lsn
+            .map_or(0, |lsn| u8::MAX as usize + 1 - lsn as usize)
+    }
+    fn last_lsn(&self) -> Option<u8> {
+        match self.current_lsn {
+            Some(current_lsn) => current_lsn.checked_sub(1),
+            None => Some(u8::MAX),
+        }
+    }
+    fn take_buffer(&mut self) -> Vec<Log> {
+        std::mem::take(&mut self.buffer)
+    }
+    fn flush(log_manager: &RwLock<LogManager>, lsn: u8) {
+        let group_commit = log_manager.read().unwrap().group_commit.clone();
+        group_commit.flush(log_manager, lsn, false);
+    }
+    // Skips, and cuts short, the commit delay. For page writes, which hold
+    // the buffer pool lock and so stall every page access while they wait.
+    fn flush_urgently(log_manager: &RwLock<LogManager>, lsn: u8) {
+        let group_commit = log_manager.read().unwrap().group_commit.clone();
+        group_commit.flush(log_manager, lsn, true);
+    }
+    fn flush_all(log_manager: &RwLock<LogManager>) {
+        let last_lsn = log_manager.read().unwrap().last_lsn();
+        if let Some(last_lsn) = last_lsn {
+            LogManager::flush(log_manager, last_lsn);
+        }
+    }
+    // Removes every segment whose records are all older than min_lsn.
+    fn truncate(&self, min_lsn: u8) {
+        let min_lsn = self
+            .backup_start_lsns
+            .iter()
+            .copied()
+            .chain(LogicalPosition::restart_lsns(&self.dir_name))
+            .chain(BackupLabel::start_lsns(&self.dir_name))
+            .fold(min_lsn, u8::min);
+        let segment_lsns = LogManager::segment_lsns(&self.dir_name);
+        for segments in segment_lsns.windows(2) {
+            if segments[1] <= min_lsn {
+                fs::remove_file(LogManager::segment_path(&self.dir_name, segments[0])).unwrap();
+            }
+        }
+    }
+    fn set_commit_delay(&self, commit_delay: Duration) {
+        self.group_commit.state.lock().unwrap().commit_delay = commit_delay;
+    }
+    fn set_segment_size(&self, segment_size: usize) {
+        self.group_commit.writer.lock().unwrap().segment_size = segment_size;
+    }
+    fn set_archive_dir(&self, archive_dir_name: &str) {
+        fs::create_dir_all(archive_dir_name).unwrap();
+        self.group_commit.writer.lock().unwrap().archive_dir_name =
+            Some(archive_dir_name.to_string());
+    }
+    fn switch_segment(log_manager: &RwLock<LogManager>) {
+        LogManager::flush_all(log_manager);
+        let group_commit = log_manager.read().unwrap().group_commit.clone();
+        group_commit.writer.lock().unwrap().complete();
+    }
+    fn flush_count(&self) -> u64 {
+        self.group_commit.state.lock().unwrap().flush_count
+    }
+}
+
+// Reads records in lsn order across all segments, stopping at the first torn
+// or corrupt record.
+struct LogReader {
+    dir_name: String,
+    segment_lsns: VecDeque<u8>,
+    segment: Option<(u8, Vec<u8>, usize)>,
+    torn_at: Option<(u8, usize)>,
+}
+
+impl LogReader {
+    fn open(dir_name: &str) -> Self {
+        Self {
+            dir_name: dir_name.to_string(),
+            segment_lsns: LogManager::segment_lsns(dir_name).into(),
+            segment: None,
+            torn_at: None,
+        }
+    }
+    // Reads a single segment file, e.g. one copied out of an archive.
+    fn open_segment(file_name: &str) -> Self {
+        let path = Path::new(file_name);
+        let segment_lsn = path
+            .file_stem()
+            .and_then(|stem| stem.to_str()?.parse().ok())
+            .unwrap_or(0);
+        Self {
+            dir_name: path.parent().unwrap().to_str().unwrap().to_string(),
+            segment_lsns: VecDeque::new(),
+            segment: Some((segment_lsn, fs::read(path).unwrap(), 0)),
+            torn_at: None,
+        }
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = Log;
+
+    fn next(&mut self) -> Option<Log> {
+        loop {
+            if self.torn_at.is_some() {
+                return None;
+            }
+            match self.segment {
+                Some((segment_lsn, ref bytes, ref mut offset)) => {
+                    if *offset == bytes.len() {
+                        self.segment = None;
+                        continue;
+                    }
+                    match Log::deserialize(&bytes[*offset..]) {
+                        Some((log, log_size)) => {
+                            *offset += log_size;
+                            return Some(log);
+                        }
+                        None => self.torn_at = Some((segment_lsn, *offset)),
+                    }
+                }
+                None => {
+                    let segment_lsn = self.segment_lsns.pop_front()?;
+                    let bytes =
+                        fs::read(LogManager::segment_path(&self.dir_name, segment_lsn)).unwrap();
+                    self.segment = Some((segment_lsn, bytes, 0));
+                }
+            }
+        }
+    }
+}
+
+// Appends to the newest segment and starts a new one, named by the lsn of its
+// first record, once the current one is full.
+// Completed segments are copied into archive_dir_name, if set.
+struct SegmentWriter {
+    dir_name: String,
+    archive_dir_name: Option<String>,
+    segment_size: usize,
+    file: File,
+    start_lsn: u8,
+    size: usize,
+    is_completed: bool,
+}
+
+impl SegmentWriter {
+    fn open(dir_name: &str, start_lsn: u8) -> Self {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(LogManager::segment_path(dir_name, start_lsn))
+            .unwrap();
+        let size = file.metadata().unwrap().len() as usize;
+        Self {
+            dir_name: dir_name.to_string(),
+            archive_dir_name: None,
+            segment_size: DEFAULT_LOG_SEGMENT_SIZE,
+            file,
+            start_lsn,
+            size,
+            is_completed: false,
+        }
+    }
+    fn write(&mut self, logs: &[Log]) {
+        for log in logs {
+            let bytes = log.serialize();
+            if self.is_completed || self.size > 0 && self.size + bytes.len() > self.segment_size {
+                self.complete();
+                let archive_dir_name = self.archive_dir_name.take();
+                let segment_size = self.segment_size;
+                *self = SegmentWriter::open(&self.dir_name, log.lsn);
+                self.archive_dir_name = archive_dir_name;
+                self.segment_size = segment_size;
+                File::open(&self.dir_name).unwrap().sync_all().unwrap();
+            }
+            self.file.write_all(&bytes).unwrap();
+            self.size += bytes.len();
+        }
+        self.file.sync_all().unwrap();
+    }
+    // Closes the current segment for writing; the next record starts a new one.
+    fn complete(&mut self) {
+        if self.is_completed || self.size == 0 {
+            return;
+        }
         self.file.sync_all().unwrap();
-        self.buffer.clear();
+        if let Some(ref archive_dir_name) = self.archive_dir_name {
+            let archived_path = LogManager::segment_path(archive_dir_name, self.start_lsn);
+            fs::copy(
+                LogManager::segment_path(&self.dir_name, self.start_lsn),
+                &archived_path,
+            )
+            .unwrap();
+            File::open(archived_path).unwrap().sync_all().unwrap();
+        }
+        self.is_completed = true;
+    }
+}
+
+// Committers wait here until their LSN is durable. The first one to arrive
+// while no flush is running becomes the leader, waits commit_delay for others
+// to append, and then writes and syncs everything buffered so far.
+struct GroupCommit {
+    state: Mutex<GroupCommitState>,
+    condvar: Condvar,
+    writer: Mutex<SegmentWriter>,
+    // Every durable batch of records is sent to each LogStream, i.e. to
+    // standbys and logical decoding.
+    subscribers: Mutex<Vec<Sender<Vec<Log>>>>,
+}
+
+struct GroupCommitState {
+    flushing: bool,
+    durable_lsn: Option<u8>,
+    commit_delay: Duration,
+    // Set while an urgent flush waits for a flush sleeping commit_delay.
+    is_urgent: bool,
+    flush_count: u64,
+}
+
+impl GroupCommit {
+    fn new(writer: SegmentWriter, durable_lsn: Option<u8>) -> Self {
+        Self {
+            state: Mutex::new(GroupCommitState {
+                flushing: false,
+                durable_lsn,
+                commit_delay: Duration::ZERO,
+                is_urgent: false,
+                flush_count: 0,
+            }),
+            condvar: Condvar::new(),
+            writer: Mutex::new(writer),
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+    fn flush(&self, log_manager: &RwLock<LogManager>, lsn: u8, is_urgent: bool) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state
+                .durable_lsn
+                .is_some_and(|durable_lsn| durable_lsn >= lsn)
+            {
+                return;
+            }
+            if !state.flushing {
+                break;
+            }
+            if is_urgent {
+                state.is_urgent = true;
+                self.condvar.notify_all();
+            }
+            state = self.condvar.wait(state).unwrap();
+        }
+        state.flushing = true;
+        if !is_urgent && !state.commit_delay.is_zero() {
+            let commit_delay = state.commit_delay;
+            state = self
+                .condvar
+                .wait_timeout_while(state, commit_delay, |state| !state.is_urgent)
+                .unwrap()
+                .0;
+        }
+        state.is_urgent = false;
+        drop(state);
+
+        let logs = log_manager.write().unwrap().take_buffer();
+        self.writer.lock().unwrap().write(&logs);
+        if !logs.is_empty() {
+            self.subscribers
+                .lock()
+                .unwrap()
+                .retain(|subscriber| subscriber.send(logs.clone()).is_ok());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.flushing = false;
+        state.flush_count += 1;
+        if let Some(log) = logs.last() {
+            state.durable_lsn = Some(log.lsn);
+        }
+        self.condvar.notify_all();
+    }
+}
+
+// Lets a commit wait until enough standbys have flushed its commit record. A
+// commit that times out switches replication to async mode, until the
+// standbys have caught up with the latest commit again.
+struct SyncReplication {
+    state: Mutex<SyncReplicationState>,
+    condvar: Condvar,
+}
+
+struct SyncReplicationState {
+    // acks a commit waits for, 0 for async replication
+    standbys: usize,
+    timeout: Duration,
+    is_degraded: bool,
+    commit_lsn: Option<u8>,
+    next_standby_id: usize,
+    // standby id -> lsn the standby has flushed, once it has sent an ack
+    flushed_lsns: HashMap<usize, u8>,
+}
+
+impl SyncReplicationState {
+    fn acks(&self, lsn: u8) -> usize {
+        self.flushed_lsns
+            .values()
+            .filter(|flushed_lsn| **flushed_lsn >= lsn)
+            .count()
+    }
+}
+
+impl SyncReplication {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(SyncReplicationState {
+                standbys: 0,
+                timeout: Duration::ZERO,
+                is_degraded: false,
+                commit_lsn: None,
+                next_standby_id: 0,
+                flushed_lsns: HashMap::new(),
+            }),
+            condvar: Condvar::new(),
+        }
+    }
+    fn configure(&self, standbys: usize, timeout: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.standbys = standbys;
+        state.timeout = timeout;
+        state.is_degraded = false;
+    }
+    fn register(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.next_standby_id += 1;
+        state.next_standby_id
+    }
+    fn ack(&self, standby_id: usize, lsn: u8) {
+        let mut state = self.state.lock().unwrap();
+        state.flushed_lsns.insert(standby_id, lsn);
+        if state.is_degraded
+            && state
+                .commit_lsn
+                .is_some_and(|commit_lsn| state.acks(commit_lsn) >= state.standbys)
+        {
+            state.is_degraded = false;
+        }
+        self.condvar.notify_all();
+    }
+    fn disconnect(&self, standby_id: usize) {
+        self.state.lock().unwrap().flushed_lsns.remove(&standby_id);
+    }
+    fn wait(&self, lsn: u8) {
+        let mut state = self.state.lock().unwrap();
+        if state.standbys == 0 {
+            return;
+        }
+        state.commit_lsn = Some(
+            state
+                .commit_lsn
+                .map_or(lsn, |commit_lsn| commit_lsn.max(lsn)),
+        );
+        if state.is_degraded {
+            return;
+        }
+        let (standbys, timeout) = (state.standbys, state.timeout);
+        let (mut state, result) = self
+            .condvar
+            .wait_timeout_while(state, timeout, |state| {
+                !state.is_degraded && state.acks(lsn) < standbys
+            })
+            .unwrap();
+        if result.timed_out() {
+            state.is_degraded = true;
+        }
+    }
+    fn is_degraded(&self) -> bool {
+        self.state.lock().unwrap().is_degraded
     }
 }
 
 struct RecoveryManager {
     log_manager: Arc<RwLock<LogManager>>,
     buffer_pool_manager: Arc<RwLock<BufferPoolManager>>,
-    // tx_id -> last_lsn
-    transaction_table: HashMap<u8, u8>,
+    transaction_table: HashMap<u8, TransactionTableEntry>,
+    // page_id -> rec_lsn
+    dirty_page_table: HashMap<u8, u8>,
     max_transaction_id: u8,
+    // Set once redo or undo changed a page or appended a record.
+    has_changes: bool,
+    // Pages that were rebuilt from the log.
+    corrupted_pages: Vec<PageCorrupted>,
 }
 
 impl RecoveryManager {
@@ -236,199 +887,364 @@ impl RecoveryManager {
             log_manager,
             buffer_pool_manager,
             transaction_table: HashMap::new(),
+            dirty_page_table: HashMap::new(),
             max_transaction_id: 0,
+            has_changes: false,
+            corrupted_pages: Vec::new(),
         }
     }
 
     fn run(&mut self) -> u8 {
-        let logs = self.log_manager.write().unwrap().read();
-        self.analyze(&logs);
-        self.redo(&logs);
+        let logs = self.replay();
         self.undo(&logs);
         self.max_transaction_id
     }
 
+    // Analysis and redo only. A standby stops here, since its losers may
+    // still commit on the primary.
+    fn replay(&mut self) -> Vec<Log> {
+        let logs = self.log_manager.write().unwrap().read();
+        self.analyze(&logs);
+        self.rebuild_corrupted_pages(&logs);
+        let redo_lsn = self.dirty_page_table.values().copied().min();
+        if let Some(redo_lsn) = redo_lsn {
+            let redo_start = logs.partition_point(|log| log.lsn < redo_lsn);
+            self.redo(&logs[redo_start..]);
+        }
+        logs
+    }
+
+    // Rebuilds the transaction table and the dirty page table, starting from
+    // the last completed checkpoint.
     fn analyze(&mut self, logs: &[Log]) {
-        for log in logs {
+        let mut analysis_lsn = 0;
+        let last_checkpoint = logs.iter().rev().find_map(|log| match log.log_type {
+            LogType::EndCheckpoint(ref end_checkpoint_log) => Some(end_checkpoint_log),
+            _ => None,
+        });
+        if let Some(checkpoint) = last_checkpoint {
+            self.transaction_table = checkpoint.transaction_table.iter().copied().collect();
+            self.dirty_page_table = checkpoint.dirty_page_table.iter().copied().collect();
+            self.max_transaction_id = checkpoint.max_transaction_id;
+            analysis_lsn = checkpoint.begin_checkpoint_lsn;
+        }
+        for log in logs.iter().filter(|log| log.lsn >= analysis_lsn) {
+            let Some(transaction_id) = log.log_type.transaction_id() else {
+                continue;
+            };
+            self.max_transaction_id = self.max_transaction_id.max(transaction_id);
             match log.log_type {
-                LogType::Insert(ref log_type) => {
-                    self.transaction_table
-                        .entry(log_type.transaction_id)
-                        .or_insert(log.lsn);
-                    self.transaction_table
-                        .entry(log_type.transaction_id)
-                        .and_modify(|e| {
-                            if *e < log.lsn {
-                                *e = log.lsn;
-                            }
-                        });
-                    self.max_transaction_id = self.max_transaction_id.max(log_type.transaction_id);
-                }
-                LogType::CompensateInsert(ref log_type) => {
-                    self.transaction_table
-                        .entry(log_type.transaction_id)
-                        .or_insert(log.lsn);
-                    self.transaction_table
-                        .entry(log_type.transaction_id)
-                        .and_modify(|e| {
-                            if *e < log.lsn {
-                                *e = log.lsn;
-                            }
-                        });
-                    self.max_transaction_id = self.max_transaction_id.max(log_type.transaction_id);
+                LogType::Commit(_) | LogType::Abort(_) => {
+                    self.transaction_table.remove(&transaction_id);
                 }
-                LogType::Begin(ref log_type) => {
+                _ => {
                     self.transaction_table
-                        .entry(log_type.transaction_id)
-                        .or_insert(log.lsn);
-                    self.transaction_table
-                        .entry(log_type.transaction_id)
-                        .and_modify(|e| {
-                            if *e < log.lsn {
-                                *e = log.lsn;
-                            }
-                        });
-                    self.max_transaction_id = self.max_transaction_id.max(log_type.transaction_id);
-                }
-                LogType::Commit(ref commit_log) => {
-                    self.transaction_table.remove(&commit_log.transaction_id);
-                    self.max_transaction_id =
-                        self.max_transaction_id.max(commit_log.transaction_id);
+                        .entry(transaction_id)
+                        .or_insert(TransactionTableEntry {
+                            first_lsn: log.lsn,
+                            last_lsn: log.lsn,
+                        })
+                        .last_lsn = log.lsn;
                 }
-                LogType::Abort(ref abort_log) => {
-                    self.transaction_table.remove(&abort_log.transaction_id);
-                    self.max_transaction_id = self.max_transaction_id.max(abort_log.transaction_id);
+            }
+            match log.log_type {
+                LogType::Insert(InsertLog { page_id, .. })
+                | LogType::CompensateInsert(CompensateInsertLog { page_id, .. })
+                | LogType::Delete(DeleteLog { page_id, .. })
+                | LogType::CompensateDelete(CompensateDeleteLog { page_id, .. }) => {
+                    self.dirty_page_table.entry(page_id).or_insert(log.lsn);
                 }
+                _ => {}
+            }
+        }
+    }
+
+    // A page torn by a crash while it was written fails its checksum. It is
+    // replaced by an empty page that redo brings up to date with every record
+    // for it still in the log. Changes from before the first lsn in the log
+    // are lost with the page.
+    fn rebuild_corrupted_pages(&mut self, logs: &[Log]) {
+        let mut buffer_pool_manager = self.buffer_pool_manager.write().unwrap();
+        let buffer_pool_manager = &mut *buffer_pool_manager;
+        for page_id in 0..buffer_pool_manager.page_manager.next_page_id() {
+            // A cached page is newer than the disk, e.g. on a promoted standby.
+            if buffer_pool_manager.page_frame_table.contains_key(&page_id) {
+                continue;
+            }
+            match buffer_pool_manager.page_manager.read_page(page_id) {
+                // Not crash damage, but a data file from another version.
+                Ok(_) | Err(PageCorrupted::UnsupportedFormatVersion { .. }) => continue,
+                Err(error) => self.corrupted_pages.push(error),
             }
+            buffer_pool_manager
+                .page_manager
+                .write_page(&Page::init(page_id));
+            if let Some(log) = logs.first() {
+                self.dirty_page_table.insert(page_id, log.lsn);
+            }
+            self.has_changes = true;
         }
     }
 
-    fn redo(&self, logs: &[Log]) {
+    // Repeats history: reapplies every change, including compensations, that
+    // may not have reached the disk.
+    fn redo(&mut self, logs: &[Log]) {
         for log in logs {
-            match log.log_type {
-                LogType::Insert(ref insert_log) => {
-                    let page_arc = self
-                        .buffer_pool_manager
-                        .write()
-                        .unwrap()
-                        .read_page(insert_log.page_id);
-                    let mut is_dirty = false;
-                    {
-                        let mut page = page_arc.write().unwrap();
-                        if page.page_lsn() < log.lsn {
-                            page.insert_tuple(insert_log.tuple, None);
-                            is_dirty = true;
-                        }
-                    }
-                    self.buffer_pool_manager
-                        .write()
-                        .unwrap()
-                        .unpin_page(insert_log.page_id, is_dirty);
-                }
-                LogType::CompensateInsert(ref compensate_insert_log) => {
-                    let page_arc = self
-                        .buffer_pool_manager
-                        .write()
-                        .unwrap()
-                        .read_page(compensate_insert_log.page_id);
-                    let mut is_dirty = false;
-                    {
-                        let mut page = page_arc.write().unwrap();
-                        if page.page_lsn() < log.lsn {
+            let Some(page_id) = log.log_type.page_id() else {
+                continue;
+            };
+            match self.dirty_page_table.get(&page_id) {
+                Some(rec_lsn) if *rec_lsn <= log.lsn => {}
+                _ => continue,
+            }
+            if RecoveryManager::redo_log(&self.buffer_pool_manager, log) {
+                self.has_changes = true;
+            }
+        }
+    }
+
+    // Applies a single page change unless the page already has it. Returns
+    // whether it did.
+    fn redo_log(buffer_pool_manager: &RwLock<BufferPoolManager>, log: &Log) -> bool {
+        let Some(page_id) = log.log_type.page_id() else {
+            return false;
+        };
+        let page_arc = {
+            let mut buffer_pool_manager = buffer_pool_manager.write().unwrap();
+            buffer_pool_manager
+                .page_manager
+                .allocate_pages_through(page_id);
+            buffer_pool_manager.read_page(page_id)
+        };
+        let mut is_dirty = false;
+        {
+            let mut page = page_arc.write().unwrap();
+            if page.page_lsn() < log.lsn {
+                match log.log_type {
+                    LogType::Insert(ref insert_log) => {
+                        page.redo_insert(
+                            insert_log.slot_id,
+                            insert_log.tuple,
+                            insert_log.transaction_id,
+                        );
+                    }
+                    // A slot past the end held a row lost with a corrupted page.
+                    LogType::CompensateInsert(ref compensate_insert_log) => {
+                        if compensate_insert_log.slot_id < page.tuple_length() {
                             page.rollback_insert(compensate_insert_log.slot_id, None);
-                            is_dirty = true;
                         }
                     }
-                    self.buffer_pool_manager
-                        .write()
-                        .unwrap()
-                        .unpin_page(compensate_insert_log.page_id, is_dirty);
+                    LogType::Delete(ref delete_log) => {
+                        if delete_log.slot_id < page.tuple_length() {
+                            page.delete_tuple(delete_log.slot_id, delete_log.transaction_id, None);
+                        }
+                    }
+                    LogType::CompensateDelete(ref compensate_delete_log) => {
+                        if compensate_delete_log.slot_id < page.tuple_length() {
+                            page.rollback_delete(compensate_delete_log.slot_id, None);
+                        }
+                    }
+                    _ => unreachable!(),
                 }
-                _ => {}
+                page.set_page_lsn(log.lsn);
+                is_dirty = true;
             }
         }
+        buffer_pool_manager
+            .write()
+            .unwrap()
+            .unpin_page(page_id, is_dirty);
+        is_dirty
     }
 
-    fn undo(&self, logs: &[Log]) {
-        let mut lsn_table = HashMap::new();
-        for (i, log) in logs.iter().enumerate() {
-            lsn_table.insert(log.lsn, i);
+    // Rolls back every loser, always undoing the largest lsn first. Each undone
+    // insert gets a CLR whose next_compenstate_lsn points at the next record to
+    // undo, so a crash during undo never undoes the same insert twice.
+    fn undo(&mut self, logs: &[Log]) {
+        let mut undo_next_lsns = self.undo_next_lsns();
+        if !undo_next_lsns.is_empty() {
+            self.has_changes = true;
         }
-        for (_, last_lsn) in self.transaction_table.iter() {
-            let mut lsn = *last_lsn;
-            let log_index = lsn_table[&lsn];
-            match logs[log_index].log_type {
-                LogType::CompensateInsert(ref compensate_insert_log) => {
-                    lsn = compensate_insert_log.next_compenstate_lsn;
+        while self.undo_step(logs, &mut undo_next_lsns) {}
+        LogManager::flush_all(&self.log_manager);
+    }
+
+    // tx_id -> lsn of the next record to undo, for every loser.
+    fn undo_next_lsns(&self) -> HashMap<u8, u8> {
+        self.transaction_table
+            .iter()
+            .map(|(transaction_id, entry)| (*transaction_id, entry.last_lsn))
+            .collect()
+    }
+
+    // Undoes the record with the largest lsn. Returns false once every loser
+    // is rolled back.
+    fn undo_step(&mut self, logs: &[Log], undo_next_lsns: &mut HashMap<u8, u8>) -> bool {
+        let Some((transaction_id, lsn)) = undo_next_lsns
+            .iter()
+            .map(|(transaction_id, lsn)| (*transaction_id, *lsn))
+            .max_by_key(|(_, lsn)| *lsn)
+        else {
+            return false;
+        };
+        let log_index = logs.binary_search_by_key(&lsn, |log| log.lsn).unwrap();
+        match logs[log_index].log_type {
+            LogType::Insert(ref insert_log) => {
+                let page_arc = self
+                    .buffer_pool_manager
+                    .write()
+                    .unwrap()
+                    .read_page(insert_log.page_id);
+                {
+                    let mut page = page_arc.write().unwrap();
+                    let log = self
+                        .log_manager
+                        .write()
+                        .unwrap()
+                        .append(LogType::CompensateInsert(CompensateInsertLog {
+                            next_compenstate_lsn: insert_log.prev_lsn,
+                            transaction_id,
+                            page_id: insert_log.page_id,
+                            slot_id: insert_log.slot_id,
+                        }));
+                    page.rollback_insert(insert_log.slot_id, None);
+                    page.set_page_lsn(log.lsn);
                 }
-                LogType::Insert(_) => {}
-                LogType::Begin(_) => {}
-                LogType::Commit(_) => {}
-                LogType::Abort(_) => {}
+                self.buffer_pool_manager
+                    .write()
+                    .unwrap()
+                    .unpin_page(insert_log.page_id, true);
+                undo_next_lsns.insert(transaction_id, insert_log.prev_lsn);
             }
-            loop {
-                let log_index = lsn_table[&lsn];
-                let log = &logs[log_index];
-                match &log.log_type {
-                    LogType::Insert(insert_log) => {
-                        let page_arc = self
-                            .buffer_pool_manager
-                            .write()
-                            .unwrap()
-                            .read_page(insert_log.page_id);
-                        {
-                            let mut page = page_arc.write().unwrap();
-                            page.rollback_insert(insert_log.slot_id, None);
-                        }
-                        self.buffer_pool_manager
-                            .write()
-                            .unwrap()
-                            .read_page(insert_log.page_id);
-                        lsn = insert_log.prev_lsn;
-                    }
-                    LogType::Begin(_) => {
-                        break;
-                    }
-                    _ => {}
+            LogType::CompensateInsert(ref compensate_insert_log) => {
+                undo_next_lsns.insert(transaction_id, compensate_insert_log.next_compenstate_lsn);
+            }
+            LogType::Delete(ref delete_log) => {
+                let page_arc = self
+                    .buffer_pool_manager
+                    .write()
+                    .unwrap()
+                    .read_page(delete_log.page_id);
+                {
+                    let mut page = page_arc.write().unwrap();
+                    let log = self
+                        .log_manager
+                        .write()
+                        .unwrap()
+                        .append(LogType::CompensateDelete(CompensateDeleteLog {
+                            next_compenstate_lsn: delete_log.prev_lsn,
+                            transaction_id,
+                            page_id: delete_log.page_id,
+                            slot_id: delete_log.slot_id,
+                        }));
+                    page.rollback_delete(delete_log.slot_id, None);
+                    page.set_page_lsn(log.lsn);
                 }
+                self.buffer_pool_manager
+                    .write()
+                    .unwrap()
+                    .unpin_page(delete_log.page_id, true);
+                undo_next_lsns.insert(transaction_id, delete_log.prev_lsn);
+            }
+            LogType::CompensateDelete(ref compensate_delete_log) => {
+                undo_next_lsns.insert(transaction_id, compensate_delete_log.next_compenstate_lsn);
+            }
+            _ => {
+                self.log_manager
+                    .write()
+                    .unwrap()
+                    .append(LogType::Abort(AbortLog { transaction_id }));
+                undo_next_lsns.remove(&transaction_id);
+                self.transaction_table.remove(&transaction_id);
             }
         }
+        true
     }
 }
 
 struct Transaction {
     transaction_id: u8,
     lock_manager: Arc<LockManager>,
+    commit_status: Arc<CommitStatusTable>,
     log_manager: Arc<RwLock<LogManager>>,
     logs: Vec<Log>,
+    // Set for the read-only transactions of a standby.
+    snapshot: Option<StandbySnapshot>,
+    // Set for the transactions of a primary.
+    mvcc_snapshot: Option<MvccSnapshot>,
+    // Can be changed between statements.
+    lock_wait: LockWait,
+    isolation_level: IsolationLevel,
 }
 
 impl Transaction {
     fn new(
         transaction_id: u8,
         lock_manager: Arc<LockManager>,
+        commit_status: Arc<CommitStatusTable>,
         log_manager: Arc<RwLock<LogManager>>,
     ) -> Self {
         Self {
             transaction_id,
             lock_manager,
+            commit_status,
             log_manager,
             logs: Vec::new(),
+            snapshot: None,
+            mvcc_snapshot: None,
+            lock_wait: LockWait::Block,
+            isolation_level: IsolationLevel::Snapshot,
         }
     }
-    fn pre_read(&mut self, page_id: u8, slot_id: u8) {
-        self.lock_manager.lock(
-            RowID(page_id, slot_id),
-            self.transaction_id,
-            LockType::Shared,
-        );
+    fn is_visible(&self, page_id: u8, slot_id: u8) -> bool {
+        self.snapshot
+            .as_ref()
+            .is_none_or(|snapshot| !snapshot.invisible_slots.contains(&(page_id, slot_id)))
     }
-    fn log_insert(&mut self, page_id: u8, slot_id: u8, tuple: u8) -> u8 {
+    // Whether the tuple in the slot is deleted for a reader holding its row
+    // lock, or for a standby reader, whose snapshot still sees the deletes of
+    // transactions the primary had not finished.
+    fn is_deleted(&self, page: &Page, slot_id: u8) -> bool {
+        page.xmax(slot_id) != INVALID_TRANSACTION_ID
+            && self.snapshot.as_ref().is_none_or(|snapshot| {
+                !snapshot
+                    .undeleted_slots
+                    .contains(&(page.page_id(), slot_id))
+            })
+    }
+    fn lock(&mut self, resource: LockResource, lock_type: LockType) -> Result<(), LockError> {
+        // Nothing on a standby writes, except replay, which cancels readers.
+        if self.snapshot.is_some() {
+            return Ok(());
+        }
         self.lock_manager.lock(
-            RowID(page_id, slot_id),
+            resource,
             self.transaction_id,
+            lock_type,
+            self.lock_wait.deadline(),
+        )
+    }
+    // Replay cancels readers before it changes a page, so a read checked
+    // afterwards saw no change.
+    fn check_replay_conflict(&self) -> Result<(), LockError> {
+        match self.snapshot {
+            Some(ref snapshot) if snapshot.canceled.load(Ordering::Relaxed) => {
+                Err(LockError::ReplayConflict)
+            }
+            _ => Ok(()),
+        }
+    }
+    fn pre_read(&mut self, page_id: u8, slot_id: u8) -> Result<(), LockError> {
+        if self.isolation_level == IsolationLevel::Snapshot {
+            return Ok(());
+        }
+        self.lock(LockResource::Row(RowID(page_id, slot_id)), LockType::Shared)
+    }
+    fn log_insert(&mut self, page_id: u8, slot_id: u8, tuple: u8) -> Result<u8, LockError> {
+        self.lock(
+            LockResource::Row(RowID(page_id, slot_id)),
             LockType::Exclusive,
-        );
+        )?;
         let log = self
             .log_manager
             .write()
@@ -442,14 +1258,11 @@ impl Transaction {
             }));
         let lsn = log.lsn;
         self.logs.push(log);
-        lsn
+        Ok(lsn)
     }
+    // Needs no lock: log_insert X locked the row, and the lock, or the table
+    // lock it was escalated to, is held until the transaction ends.
     fn log_compensate_insert(&mut self, page_id: u8, slot_id: u8, next_lsn: u8) -> u8 {
-        self.lock_manager.lock(
-            RowID(page_id, slot_id),
-            self.transaction_id,
-            LockType::Exclusive,
-        );
         let log = self
             .log_manager
             .write()
@@ -464,6 +1277,37 @@ impl Transaction {
         self.logs.push(log);
         lsn
     }
+    // The caller X locks the row before latching its page.
+    fn log_delete(&mut self, page_id: u8, slot_id: u8) -> u8 {
+        let log = self
+            .log_manager
+            .write()
+            .unwrap()
+            .append(LogType::Delete(DeleteLog {
+                prev_lsn: self.prev_lsn(),
+                transaction_id: self.transaction_id,
+                page_id,
+                slot_id,
+            }));
+        let lsn = log.lsn;
+        self.logs.push(log);
+        lsn
+    }
+    fn log_compensate_delete(&mut self, page_id: u8, slot_id: u8, next_lsn: u8) -> u8 {
+        let log = self
+            .log_manager
+            .write()
+            .unwrap()
+            .append(LogType::CompensateDelete(CompensateDeleteLog {
+                next_compenstate_lsn: next_lsn,
+                transaction_id: self.transaction_id,
+                page_id,
+                slot_id,
+            }));
+        let lsn = log.lsn;
+        self.logs.push(log);
+        lsn
+    }
     fn log_begin(&mut self) {
         let log = self
             .log_manager
@@ -481,6 +1325,10 @@ impl Transaction {
             .unwrap()
             .append(LogType::Commit(CommitLog {
                 transaction_id: self.transaction_id,
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as u64,
             }));
         self.logs.push(log);
     }
@@ -495,13 +1343,23 @@ impl Transaction {
         self.logs.push(log);
     }
     fn commit(&mut self) {
+        if self.snapshot.take().is_some() {
+            return;
+        }
         self.log_commit();
-        self.log_manager.write().unwrap().flush();
+        LogManager::flush(&self.log_manager, self.prev_lsn());
+        let sync_replication = self.log_manager.read().unwrap().sync_replication.clone();
+        sync_replication.wait(self.prev_lsn());
+        self.commit_status.commit(self.transaction_id);
         self.lock_manager.unlock(self.transaction_id);
     }
     fn abort(&mut self) {
+        if self.snapshot.take().is_some() {
+            return;
+        }
         self.log_abort();
-        self.log_manager.write().unwrap().flush();
+        LogManager::flush(&self.log_manager, self.prev_lsn());
+        self.commit_status.abort(self.transaction_id);
         self.lock_manager.unlock(self.transaction_id);
     }
     fn prev_lsn(&self) -> u8 {
@@ -509,45 +1367,326 @@ impl Transaction {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum LockType {
+    IntentionShared,
+    IntentionExclusive,
     Shared,
+    SharedIntentionExclusive,
     Exclusive,
 }
 
-#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+impl LockType {
+    fn is_compatible(self, other: LockType) -> bool {
+        use LockType::*;
+        matches!(
+            (self, other),
+            (
+                IntentionShared,
+                IntentionShared | IntentionExclusive | Shared | SharedIntentionExclusive
+            ) | (IntentionExclusive, IntentionShared | IntentionExclusive)
+                | (Shared, IntentionShared | Shared)
+                | (SharedIntentionExclusive, IntentionShared)
+        )
+    }
+    fn covers(self, other: LockType) -> bool {
+        use LockType::*;
+        match self {
+            IntentionShared => other == IntentionShared,
+            IntentionExclusive => matches!(other, IntentionShared | IntentionExclusive),
+            Shared => matches!(other, IntentionShared | Shared),
+            SharedIntentionExclusive => other != Exclusive,
+            Exclusive => true,
+        }
+    }
+    // The weakest mode covering both, used when a transaction locks a resource
+    // it already holds.
+    fn combine(self, other: LockType) -> LockType {
+        if self.covers(other) {
+            self
+        } else if other.covers(self) {
+            other
+        } else {
+            LockType::SharedIntentionExclusive
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self {
+            LockType::IntentionShared => "IS",
+            LockType::IntentionExclusive => "IX",
+            LockType::Shared => "S",
+            LockType::SharedIntentionExclusive => "SIX",
+            LockType::Exclusive => "X",
+        }
+    }
+    // The mode the parents of a resource locked in this mode are locked in.
+    fn intention(self) -> LockType {
+        match self {
+            LockType::IntentionShared | LockType::Shared => LockType::IntentionShared,
+            _ => LockType::IntentionExclusive,
+        }
+    }
+    // Whether holding this mode on a resource locks its children in the given
+    // mode without locking them one by one.
+    fn locks_children(self, lock_type: LockType) -> bool {
+        match self {
+            LockType::Shared | LockType::SharedIntentionExclusive => {
+                matches!(lock_type, LockType::IntentionShared | LockType::Shared)
+            }
+            LockType::Exclusive => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LockError {
+    // Waiting would have closed a cycle in the wait-for graph. The transaction
+    // has to abort, after which it can be retried.
+    Deadlock,
+    // The lock was not granted before the deadline of the transaction's
+    // LockWait.
+    LockTimeout,
+    // A read-only transaction of a standby tried to write.
+    ReadOnlyTransaction,
+    // Replay on a standby canceled this read-only transaction after waiting
+    // max_standby_delay for it.
+    ReplayConflict,
+    // A snapshot transaction tried to delete a row that a transaction it does
+    // not see deleted first. It has to abort, after which it can be retried.
+    SerializationFailure,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum IsolationLevel {
+    // Scans lock the pages they read.
+    RepeatableRead,
+    // Scans also lock the table in S, a predicate lock on every row, so no
+    // transaction can insert a phantom row until commit. Scans with SKIP
+    // LOCKED only lock rows and are not protected.
+    Serializable,
+    // Scans take no locks and see the rows committed before the transaction
+    // began, so readers and writers never wait for each other. The default.
+    Snapshot,
+}
+
+// How a transaction waits for a lock held by another transaction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LockWait {
+    Block,
+    Timeout(Duration),
+    // Fails right away instead of waiting.
+    NoWait,
+    // Scans skip the rows locked by others. Other locks behave as with NoWait.
+    SkipLocked,
+}
+
+impl LockWait {
+    fn deadline(&self) -> Option<Instant> {
+        match self {
+            LockWait::Block => None,
+            LockWait::Timeout(timeout) => Some(Instant::now() + *timeout),
+            LockWait::NoWait | LockWait::SkipLocked => Some(Instant::now()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
 struct RowID(u8, u8);
 
+// The database has a single table, made of pages, made of rows.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+enum LockResource {
+    Table,
+    Page(u8),
+    Row(RowID),
+}
+
+impl LockResource {
+    fn name(&self) -> String {
+        match self {
+            LockResource::Table => "table".to_string(),
+            LockResource::Page(page_id) => format!("page {}", page_id),
+            LockResource::Row(RowID(page_id, slot_id)) => format!("row {}:{}", page_id, slot_id),
+        }
+    }
+    // From the table down.
+    fn parents(&self) -> Vec<LockResource> {
+        match self {
+            LockResource::Table => vec![],
+            LockResource::Page(_) => vec![LockResource::Table],
+            LockResource::Row(RowID(page_id, _)) => {
+                vec![LockResource::Table, LockResource::Page(*page_id)]
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct LockSnapshot {
+    resource: LockResource,
+    lock_type: LockType,
+    transaction_id: u8,
+    // None once granted
+    wait_time: Option<Duration>,
+}
+
 struct LockManager {
-    locks: Mutex<HashMap<RowID, Arc<SharedExclusiveLock>>>,
-    transaction_locks_table: Mutex<HashMap<u8, Vec<(RowID, LockType)>>>,
+    locks: Mutex<HashMap<LockResource, Arc<Lock>>>,
+    transaction_locks_table: Mutex<HashMap<u8, HashSet<LockResource>>>,
+    wait_for_graph: WaitForGraph,
+    // Row locks a transaction can hold before they are escalated.
+    escalation_threshold: AtomicUsize,
+    // Bytes the lock table can use before a transaction taking a row lock
+    // escalates, however few row locks it holds.
+    memory_budget: AtomicUsize,
+    row_lock_counts: Mutex<HashMap<u8, RowLockCount>>,
+    // Entries of transaction_locks_table, so memory_usage needs no scan.
+    held_lock_count: AtomicUsize,
+}
+
+#[derive(Default)]
+struct RowLockCount {
+    rows: usize,
+    // Raised after an escalation is not granted, so the transaction does not
+    // try again on every row lock.
+    next_escalation: usize,
 }
 
 impl LockManager {
+    const DEFAULT_ESCALATION_THRESHOLD: usize = 64;
+    const DEFAULT_MEMORY_BUDGET: usize = 1 << 20;
+
     fn new() -> Self {
         Self {
             locks: Mutex::new(HashMap::new()),
             transaction_locks_table: Mutex::new(HashMap::new()),
+            wait_for_graph: WaitForGraph::default(),
+            escalation_threshold: AtomicUsize::new(Self::DEFAULT_ESCALATION_THRESHOLD),
+            memory_budget: AtomicUsize::new(Self::DEFAULT_MEMORY_BUDGET),
+            row_lock_counts: Mutex::new(HashMap::new()),
+            held_lock_count: AtomicUsize::new(0),
+        }
+    }
+    // Locks the parents of the resource in the intention mode first, unless
+    // one of them already locks the resource.
+    fn lock(
+        &self,
+        resource: LockResource,
+        transaction_id: u8,
+        lock_type: LockType,
+        deadline: Option<Instant>,
+    ) -> Result<(), LockError> {
+        let parents = resource.parents();
+        let is_locked_by_parent = parents.iter().any(|parent| {
+            self.lock_type(*parent, transaction_id)
+                .is_some_and(|parent_lock_type| parent_lock_type.locks_children(lock_type))
+        });
+        if is_locked_by_parent {
+            return Ok(());
+        }
+        for parent in parents {
+            self.lock_resource(parent, transaction_id, lock_type.intention(), deadline)?;
+        }
+        self.lock_resource(resource, transaction_id, lock_type, deadline)?;
+        if let LockResource::Row(_) = resource {
+            self.escalate(transaction_id);
+        }
+        Ok(())
+    }
+    // Swaps the page and row locks of a transaction for a single table lock
+    // once it holds too many row locks, or the lock table is over its memory
+    // budget. Backs off, to try again once the row locks have doubled, if the
+    // table lock is not granted right away.
+    fn escalate(&self, transaction_id: u8) {
+        let rows = {
+            let counts = self.row_lock_counts.lock().unwrap();
+            match counts.get(&transaction_id) {
+                Some(count) if count.rows >= count.next_escalation => count.rows,
+                _ => return,
+            }
+        };
+        if rows <= self.escalation_threshold.load(Ordering::Relaxed)
+            && self.memory_usage() <= self.memory_budget.load(Ordering::Relaxed)
+        {
+            return;
+        }
+        let lock_type = match self
+            .lock_type(LockResource::Table, transaction_id)
+            .map(LockType::intention)
+        {
+            Some(LockType::IntentionExclusive) => LockType::Exclusive,
+            _ => LockType::Shared,
+        };
+        if self
+            .lock_resource(
+                LockResource::Table,
+                transaction_id,
+                lock_type,
+                Some(Instant::now()),
+            )
+            .is_err()
+        {
+            if let Some(count) = self
+                .row_lock_counts
+                .lock()
+                .unwrap()
+                .get_mut(&transaction_id)
+            {
+                count.next_escalation = rows * 2;
+            }
+            return;
         }
+        let resources: Vec<LockResource> = {
+            let mut table = self.transaction_locks_table.lock().unwrap();
+            let held = table.get_mut(&transaction_id).unwrap();
+            let resources = held
+                .iter()
+                .copied()
+                .filter(|resource| *resource != LockResource::Table)
+                .collect();
+            held.retain(|resource| *resource == LockResource::Table);
+            resources
+        };
+        self.row_lock_counts.lock().unwrap().remove(&transaction_id);
+        self.held_lock_count
+            .fetch_sub(resources.len(), Ordering::Relaxed);
+        self.release(resources, transaction_id);
     }
-    fn lock(&self, row_id: RowID, transaction_id: u8, lock_type: LockType) {
+    fn lock_resource(
+        &self,
+        resource: LockResource,
+        transaction_id: u8,
+        lock_type: LockType,
+        deadline: Option<Instant>,
+    ) -> Result<(), LockError> {
         let lock_obj = {
             let mut guard = self.locks.lock().unwrap();
             guard
-                .entry(row_id)
-                .or_insert_with(|| Arc::new(SharedExclusiveLock::new()))
+                .entry(resource)
+                .or_insert_with(|| Arc::new(Lock::new()))
                 .clone()
         };
 
-        match lock_type {
-            LockType::Shared => lock_obj.lock_shared(transaction_id),
-            LockType::Exclusive => lock_obj.lock_exclusive(transaction_id),
-        }
+        lock_obj.lock(transaction_id, lock_type, &self.wait_for_graph, deadline)?;
 
         let mut table = self.transaction_locks_table.lock().unwrap();
-        table
-            .entry(transaction_id)
-            .or_default()
-            .push((row_id, lock_type));
+        if table.entry(transaction_id).or_default().insert(resource) {
+            self.held_lock_count.fetch_add(1, Ordering::Relaxed);
+            if let LockResource::Row(_) = resource {
+                self.row_lock_counts
+                    .lock()
+                    .unwrap()
+                    .entry(transaction_id)
+                    .or_default()
+                    .rows += 1;
+            }
+        }
+        Ok(())
+    }
+    fn lock_type(&self, resource: LockResource, transaction_id: u8) -> Option<LockType> {
+        let lock_obj = self.locks.lock().unwrap().get(&resource)?.clone();
+        lock_obj.lock_type(transaction_id)
     }
     fn unlock(&self, transaction_id: u8) {
         let locks_to_release = {
@@ -555,18 +1694,64 @@ impl LockManager {
             table.remove(&transaction_id)
         };
 
-        if let Some(locks_vec) = locks_to_release {
-            let guard = self.locks.lock().unwrap();
-            for (resource, lock_type) in locks_vec {
-                if let Some(lock_obj) = guard.get(&resource) {
-                    match lock_type {
-                        LockType::Shared => lock_obj.unlock_shared(transaction_id),
-                        LockType::Exclusive => lock_obj.unlock_exclusive(transaction_id),
-                    }
+        let locks_to_release = locks_to_release.unwrap_or_default();
+        self.row_lock_counts.lock().unwrap().remove(&transaction_id);
+        self.held_lock_count
+            .fetch_sub(locks_to_release.len(), Ordering::Relaxed);
+        self.release(locks_to_release, transaction_id);
+    }
+    fn release(&self, resources: impl IntoIterator<Item = LockResource>, transaction_id: u8) {
+        let mut locks = self.locks.lock().unwrap();
+        for resource in resources {
+            if let Some(lock_obj) = locks.get(&resource) {
+                lock_obj.unlock(transaction_id, &self.wait_for_graph);
+                // Waiters hold a clone, so nobody holds or waits for the lock.
+                if Arc::strong_count(lock_obj) == 1 && lock_obj.is_free() {
+                    locks.remove(&resource);
                 }
             }
         }
     }
+    // Every granted and waiting lock request, by resource.
+    fn snapshot(&self) -> Vec<LockSnapshot> {
+        let now = Instant::now();
+        let mut snapshots = Vec::new();
+        for (resource, lock_obj) in self.locks.lock().unwrap().iter() {
+            let state = lock_obj.state.lock().unwrap();
+            for (transaction_id, lock_type) in &state.granted {
+                snapshots.push(LockSnapshot {
+                    resource: *resource,
+                    lock_type: *lock_type,
+                    transaction_id: *transaction_id,
+                    wait_time: None,
+                });
+            }
+            for request in &state.queue {
+                snapshots.push(LockSnapshot {
+                    resource: *resource,
+                    lock_type: request.lock_type,
+                    transaction_id: request.transaction_id,
+                    wait_time: Some(now - request.since),
+                });
+            }
+        }
+        snapshots.sort_by_key(|snapshot| {
+            (
+                snapshot.resource,
+                snapshot.wait_time.is_some(),
+                snapshot.transaction_id,
+            )
+        });
+        snapshots
+    }
+    // An estimate of the bytes used by the lock table. Each granted lock is
+    // in transaction_locks_table once, so held_lock_count also counts them.
+    fn memory_usage(&self) -> usize {
+        let locks = self.locks.lock().unwrap().len();
+        let held = self.held_lock_count.load(Ordering::Relaxed);
+        locks * (size_of::<LockResource>() + size_of::<Arc<Lock>>() + size_of::<Lock>())
+            + held * (size_of::<(u8, LockType)>() + size_of::<LockResource>())
+    }
 }
 impl Default for LockManager {
     fn default() -> Self {
@@ -574,108 +1759,377 @@ impl Default for LockManager {
     }
 }
 
-struct SharedExclusiveLock {
+// Edges from every waiting transaction to the transactions holding the lock it
+// waits for. They are set each time a transaction goes to sleep, so a cycle is
+// found by the transaction that closes it, which becomes the victim.
+#[derive(Default)]
+struct WaitForGraph {
+    edges: Mutex<HashMap<u8, HashSet<u8>>>,
+}
+
+impl WaitForGraph {
+    fn wait(&self, waiter: u8, holders: HashSet<u8>) -> Result<(), LockError> {
+        let mut edges = self.edges.lock().unwrap();
+        edges.insert(waiter, holders);
+        let mut visited = HashSet::new();
+        let mut stack = vec![waiter];
+        while let Some(transaction_id) = stack.pop() {
+            for holder in edges.get(&transaction_id).into_iter().flatten() {
+                if *holder == waiter {
+                    edges.remove(&waiter);
+                    return Err(LockError::Deadlock);
+                }
+                if visited.insert(*holder) {
+                    stack.push(*holder);
+                }
+            }
+        }
+        Ok(())
+    }
+    fn stop_waiting(&self, waiter: u8) {
+        self.edges.lock().unwrap().remove(&waiter);
+    }
+    // A transaction waits for one lock at a time, so once holder leaves that
+    // lock the waiters' edges to it are stale until they wake up. A stale edge
+    // could close a cycle that does not exist.
+    fn remove_holder(&self, waiters: impl IntoIterator<Item = u8>, holder: u8) {
+        let mut edges = self.edges.lock().unwrap();
+        for waiter in waiters {
+            if let Some(holders) = edges.get_mut(&waiter) {
+                holders.remove(&holder);
+            }
+        }
+    }
+    // (waiter, holder) pairs.
+    fn edges(&self) -> Vec<(u8, u8)> {
+        let mut edges: Vec<(u8, u8)> = self
+            .edges
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(waiter, holders)| holders.iter().map(|holder| (*waiter, *holder)))
+            .collect();
+        edges.sort();
+        edges
+    }
+}
+
+struct Lock {
     state: Mutex<LockState>,
     condvar: Condvar,
 }
 
 struct LockState {
-    readers: HashSet<u8>,
-    writer: Option<u8>,
+    granted: HashMap<u8, LockType>,
+    // Requests are granted in order, so a stream of compatible requests
+    // cannot starve an incompatible one. Upgrades of a granted lock go ahead
+    // of new requests.
+    queue: VecDeque<LockRequest>,
 }
 
-impl SharedExclusiveLock {
+struct LockRequest {
+    transaction_id: u8,
+    lock_type: LockType,
+    since: Instant,
+}
+
+impl Lock {
     fn new() -> Self {
         Self {
             state: Mutex::new(LockState {
-                readers: HashSet::new(),
-                writer: None,
+                granted: HashMap::new(),
+                queue: VecDeque::new(),
             }),
             condvar: Condvar::new(),
         }
     }
 
-    fn lock_shared(&self, transaction_id: u8) {
+    fn lock(
+        &self,
+        transaction_id: u8,
+        lock_type: LockType,
+        wait_for_graph: &WaitForGraph,
+        deadline: Option<Instant>,
+    ) -> Result<(), LockError> {
         let mut state = self.state.lock().unwrap();
+        let granted = state.granted.get(&transaction_id).copied();
+        let wanted = granted.map_or(lock_type, |granted| granted.combine(lock_type));
+        if granted == Some(wanted) {
+            return Ok(());
+        }
+        let request = LockRequest {
+            transaction_id,
+            lock_type: wanted,
+            since: Instant::now(),
+        };
+        if granted.is_some() {
+            let position = state
+                .queue
+                .iter()
+                .position(|request| !state.granted.contains_key(&request.transaction_id))
+                .unwrap_or(state.queue.len());
+            state.queue.insert(position, request);
+        } else {
+            state.queue.push_back(request);
+        }
         loop {
-            match state.writer {
-                Some(w) if w != transaction_id => {
-                    state = self.condvar.wait(state).unwrap();
-                }
-                None if !state.readers.contains(&transaction_id) => {
-                    state.readers.insert(transaction_id);
-                    break;
-                }
-                _ => break,
+            let position = state
+                .queue
+                .iter()
+                .position(|request| request.transaction_id == transaction_id)
+                .unwrap();
+            let holders: HashSet<u8> = state
+                .granted
+                .iter()
+                .filter(|(holder, holder_lock_type)| {
+                    **holder != transaction_id && !holder_lock_type.is_compatible(wanted)
+                })
+                .map(|(holder, _)| *holder)
+                .chain(
+                    state
+                        .queue
+                        .iter()
+                        .take(position)
+                        .filter(|request| !request.lock_type.is_compatible(wanted))
+                        .map(|request| request.transaction_id),
+                )
+                .collect();
+            if holders.is_empty() {
+                state.queue.remove(position);
+                state.granted.insert(transaction_id, wanted);
+                // The next requests may be compatible too.
+                self.condvar.notify_all();
+                break;
             }
+            state = match self.wait(state, transaction_id, holders, wait_for_graph, deadline) {
+                Ok(state) => state,
+                Err(error) => {
+                    let mut state = self.state.lock().unwrap();
+                    state
+                        .queue
+                        .retain(|request| request.transaction_id != transaction_id);
+                    wait_for_graph.remove_holder(
+                        state.queue.iter().map(|request| request.transaction_id),
+                        transaction_id,
+                    );
+                    self.condvar.notify_all();
+                    return Err(error);
+                }
+            };
         }
+        wait_for_graph.stop_waiting(transaction_id);
+        Ok(())
     }
 
-    fn unlock_shared(&self, transaction_id: u8) {
-        let mut state = self.state.lock().unwrap();
-        state.readers.remove(&transaction_id);
-        self.condvar.notify_all();
+    // Sleeps until the lock is released or the deadline passes.
+    fn wait<'a>(
+        &self,
+        state: MutexGuard<'a, LockState>,
+        transaction_id: u8,
+        holders: HashSet<u8>,
+        wait_for_graph: &WaitForGraph,
+        deadline: Option<Instant>,
+    ) -> Result<MutexGuard<'a, LockState>, LockError> {
+        let Some(deadline) = deadline else {
+            wait_for_graph.wait(transaction_id, holders)?;
+            return Ok(self.condvar.wait(state).unwrap());
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            wait_for_graph.stop_waiting(transaction_id);
+            return Err(LockError::LockTimeout);
+        }
+        wait_for_graph.wait(transaction_id, holders)?;
+        Ok(self.condvar.wait_timeout(state, deadline - now).unwrap().0)
     }
 
-    fn lock_exclusive(&self, transaction_id: u8) {
-        let mut state = self.state.lock().unwrap();
-
-        loop {
-            if let Some(current_writer) = state.writer {
-                if current_writer != transaction_id {
-                    state = self.condvar.wait(state).unwrap();
-                    continue;
-                } else {
-                    break;
-                }
-            } else {
-                let has_read_lock = state.readers.contains(&transaction_id);
-                if has_read_lock {
-                    if state.readers.len() > 1 {
-                        state = self.condvar.wait(state).unwrap();
-                        continue;
-                    }
-                    state.readers.clear();
-                    state.writer = Some(transaction_id);
-                    break;
-                } else {
-                    if !state.readers.is_empty() {
-                        state = self.condvar.wait(state).unwrap();
-                        continue;
-                    }
-                    state.writer = Some(transaction_id);
-                    break;
-                }
-            }
-        }
+    fn lock_type(&self, transaction_id: u8) -> Option<LockType> {
+        self.state
+            .lock()
+            .unwrap()
+            .granted
+            .get(&transaction_id)
+            .copied()
     }
 
-    fn unlock_exclusive(&self, transaction_id: u8) {
+    fn unlock(&self, transaction_id: u8, wait_for_graph: &WaitForGraph) {
         let mut state = self.state.lock().unwrap();
-        if let Some(w) = state.writer {
-            assert_eq!(w, transaction_id);
-            state.writer = None;
+        if state.granted.remove(&transaction_id).is_some() {
+            wait_for_graph.remove_holder(
+                state.queue.iter().map(|request| request.transaction_id),
+                transaction_id,
+            );
             self.condvar.notify_all();
         }
     }
+
+    fn is_free(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.granted.is_empty() && state.queue.is_empty()
+    }
 }
 
-const PAGE_SIZE: usize = 16;
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TransactionStatus {
+    InProgress,
+    // Numbered in commit order.
+    Committed(u64),
+    Aborted,
+}
 
-struct Page {
-    bytes: [u8; PAGE_SIZE],
+// The status of the transactions some snapshot may not see as committed.
+// Missing transactions are committed: older ones because recovery rolled back
+// the others, pruned ones because every snapshot sees them.
+#[derive(Default)]
+struct CommitStatusTable {
+    state: Mutex<CommitStatusState>,
 }
 
-impl Page {
-    const HEADER_SIZE: usize = 3;
-    const MAX_TUPLE_LENGTH: u8 = PAGE_SIZE as u8 - Self::HEADER_SIZE as u8;
-    fn init(page_id: u8) -> Self {
-        let mut bytes = [0; PAGE_SIZE];
-        bytes[0] = page_id;
-        Self { bytes }
+#[derive(Default)]
+struct CommitStatusState {
+    statuses: HashMap<u8, TransactionStatus>,
+    last_commit_sequence_number: u64,
+    // tx_id -> commit_sequence_number of the snapshot of every running
+    // transaction
+    snapshots: HashMap<u8, u64>,
+}
+
+impl CommitStatusState {
+    // Drops the commits every running snapshot sees, and the aborts, whose
+    // tuples were rolled back before the abort was recorded. Keeps the table
+    // from growing with every transaction.
+    fn prune(&mut self) {
+        let oldest_snapshot = self
+            .snapshots
+            .values()
+            .copied()
+            .min()
+            .unwrap_or(self.last_commit_sequence_number);
+        self.statuses.retain(|_, status| match status {
+            TransactionStatus::InProgress => true,
+            TransactionStatus::Committed(commit_sequence_number) => {
+                *commit_sequence_number > oldest_snapshot
+            }
+            TransactionStatus::Aborted => false,
+        });
     }
-    fn load(bytes: [u8; PAGE_SIZE]) -> Self {
-        Self { bytes }
+}
+
+impl CommitStatusTable {
+    fn begin(commit_status: &Arc<CommitStatusTable>, transaction_id: u8) -> MvccSnapshot {
+        let mut state = commit_status.state.lock().unwrap();
+        state
+            .statuses
+            .insert(transaction_id, TransactionStatus::InProgress);
+        let commit_sequence_number = state.last_commit_sequence_number;
+        state
+            .snapshots
+            .insert(transaction_id, commit_sequence_number);
+        MvccSnapshot {
+            commit_status: commit_status.clone(),
+            transaction_id,
+            commit_sequence_number: state.last_commit_sequence_number,
+        }
+    }
+    fn commit(&self, transaction_id: u8) {
+        let mut state = self.state.lock().unwrap();
+        state.last_commit_sequence_number += 1;
+        let commit_sequence_number = state.last_commit_sequence_number;
+        state.statuses.insert(
+            transaction_id,
+            TransactionStatus::Committed(commit_sequence_number),
+        );
+        state.snapshots.remove(&transaction_id);
+        state.prune();
+    }
+    fn abort(&self, transaction_id: u8) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .statuses
+            .insert(transaction_id, TransactionStatus::Aborted);
+        state.snapshots.remove(&transaction_id);
+        state.prune();
+    }
+}
+
+// The commits a transaction sees: its own changes and the transactions
+// committed before it began.
+struct MvccSnapshot {
+    commit_status: Arc<CommitStatusTable>,
+    transaction_id: u8,
+    commit_sequence_number: u64,
+}
+
+impl MvccSnapshot {
+    // Whether a tuple created by xmin and deleted by xmax is visible: its
+    // insert is, and its delete, if any, is not.
+    fn sees(&self, xmin: u8, xmax: u8) -> bool {
+        self.sees_transaction(xmin)
+            && (xmax == INVALID_TRANSACTION_ID || !self.sees_transaction(xmax))
+    }
+    fn sees_transaction(&self, transaction_id: u8) -> bool {
+        if transaction_id == self.transaction_id {
+            return true;
+        }
+        match self
+            .commit_status
+            .state
+            .lock()
+            .unwrap()
+            .statuses
+            .get(&transaction_id)
+        {
+            None => true,
+            Some(TransactionStatus::Committed(commit_sequence_number)) => {
+                *commit_sequence_number <= self.commit_sequence_number
+            }
+            Some(_) => false,
+        }
+    }
+}
+
+const PAGE_SIZE: usize = 16;
+
+// Why a page read from the data file cannot be used.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum PageCorrupted {
+    PastEndOfFile { page_id: u8 },
+    // E.g. torn by a crash in the middle of writing it.
+    ChecksumMismatch { page_id: u8 },
+    UnsupportedFormatVersion { page_id: u8, format_version: u8 },
+    PageIdMismatch { page_id: u8, stored_page_id: u8 },
+}
+
+struct Page {
+    bytes: [u8; PAGE_SIZE],
+    // lsn of the first change since the page was last written to disk
+    rec_lsn: Option<u8>,
+}
+
+impl Page {
+    // page_id(1) + page_lsn(1) + tuple_length(1) + checksum(1) + format_version(1)
+    const HEADER_SIZE: usize = 5;
+    // Bumped whenever the page layout changes, so an older data file is
+    // rejected instead of misread.
+    const FORMAT_VERSION: u8 = 2;
+    // tuple(1) + xmin(1) + xmax(1), the ids of the transactions that inserted
+    // and deleted it
+    const SLOT_SIZE: usize = 3;
+    const MAX_TUPLE_LENGTH: u8 = ((PAGE_SIZE - Self::HEADER_SIZE) / Self::SLOT_SIZE) as u8;
+    fn init(page_id: u8) -> Self {
+        let mut bytes = [0; PAGE_SIZE];
+        bytes[0] = page_id;
+        bytes[4] = Self::FORMAT_VERSION;
+        Self {
+            bytes,
+            rec_lsn: None,
+        }
+    }
+    fn load(bytes: [u8; PAGE_SIZE]) -> Self {
+        Self {
+            bytes,
+            rec_lsn: None,
+        }
     }
     fn page_id(&self) -> u8 {
         self.bytes[0]
@@ -683,31 +2137,106 @@ impl Page {
     fn page_lsn(&self) -> u8 {
         self.bytes[1]
     }
+    fn set_page_lsn(&mut self, lsn: u8) {
+        self.bytes[1] = lsn;
+        self.rec_lsn.get_or_insert(lsn);
+    }
     fn tuple_length(&self) -> u8 {
         self.bytes[2]
     }
-    fn read_tuple(&self, tuple_index: u8, transaction: &mut Transaction) -> u8 {
-        transaction.pre_read(self.page_id(), tuple_index);
-        self.bytes[tuple_index as usize + Self::HEADER_SIZE]
+    fn checksum(&self) -> u8 {
+        self.bytes[3]
+    }
+    fn format_version(&self) -> u8 {
+        self.bytes[4]
+    }
+    // Low byte of the crc32 of the page with the checksum byte zeroed. It is
+    // only kept up to date on disk, by PageManager::write_page. Being a single
+    // byte, it still matches for 1 in 256 corrupted pages.
+    fn compute_checksum(&self) -> u8 {
+        let mut bytes = self.bytes;
+        bytes[3] = 0;
+        crc32(&bytes) as u8
+    }
+    fn slot_offset(slot_id: u8) -> usize {
+        Self::HEADER_SIZE + slot_id as usize * Self::SLOT_SIZE
+    }
+    fn xmin(&self, slot_id: u8) -> u8 {
+        self.bytes[Self::slot_offset(slot_id) + 1]
     }
-    fn read_tuples(&self, transaction: &mut Transaction) -> Vec<u8> {
+    fn xmax(&self, slot_id: u8) -> u8 {
+        self.bytes[Self::slot_offset(slot_id) + 2]
+    }
+    fn is_dead(&self, slot_id: u8) -> bool {
+        self.xmin(slot_id) == INVALID_TRANSACTION_ID
+    }
+    fn read_tuple(&self, tuple_index: u8) -> u8 {
+        self.bytes[Self::slot_offset(tuple_index)]
+    }
+    // The caller takes the locks first, as the page is latched.
+    fn read_tuples(&self, slot_ids: &[u8]) -> Vec<u8> {
         let mut result = Vec::new();
-        for i in 0..self.tuple_length() {
-            result.push(self.read_tuple(i, transaction));
+        for i in slot_ids.iter().copied() {
+            if i < self.tuple_length() && !self.is_dead(i) {
+                result.push(self.read_tuple(i));
+            }
         }
         result
     }
     fn has_space(&self) -> bool {
         self.tuple_length() < Self::MAX_TUPLE_LENGTH
     }
-    fn insert_tuple(&mut self, tuple: u8, transaction: Option<&mut Transaction>) {
+    fn insert_tuple(
+        &mut self,
+        tuple: u8,
+        xmin: u8,
+        transaction: Option<&mut Transaction>,
+    ) -> Result<(), LockError> {
         let slot_id = self.tuple_length();
         if let Some(transaction) = transaction {
-            let lsn = transaction.log_insert(self.page_id(), slot_id, tuple);
-            self.bytes[1] = lsn;
+            let lsn = transaction.log_insert(self.page_id(), slot_id, tuple)?;
+            self.set_page_lsn(lsn);
         }
-        self.bytes[slot_id as usize + Self::HEADER_SIZE] = tuple;
+        let offset = Self::slot_offset(slot_id);
+        self.bytes[offset] = tuple;
+        self.bytes[offset + 1] = xmin;
+        self.bytes[offset + 2] = INVALID_TRANSACTION_ID;
         self.bytes[2] += 1;
+        Ok(())
+    }
+    // Only stamps the slot: readers whose snapshot is older still see the
+    // tuple.
+    // Puts a logged insert back into its slot. Free slots before it belonged to
+    // rows lost with a corrupted page, so they are left dead.
+    fn redo_insert(&mut self, slot_id: u8, tuple: u8, xmin: u8) {
+        for lost_slot_id in self.tuple_length()..slot_id {
+            let offset = Self::slot_offset(lost_slot_id);
+            self.bytes[offset + 1] = INVALID_TRANSACTION_ID;
+            self.bytes[offset + 2] = INVALID_TRANSACTION_ID;
+        }
+        let offset = Self::slot_offset(slot_id);
+        self.bytes[offset] = tuple;
+        self.bytes[offset + 1] = xmin;
+        self.bytes[offset + 2] = INVALID_TRANSACTION_ID;
+        self.bytes[2] = self.tuple_length().max(slot_id + 1);
+    }
+    fn delete_tuple(&mut self, slot_id: u8, xmax: u8, transaction: Option<&mut Transaction>) {
+        if let Some(transaction) = transaction {
+            let lsn = transaction.log_delete(self.page_id(), slot_id);
+            self.set_page_lsn(lsn);
+        }
+        self.bytes[Self::slot_offset(slot_id) + 2] = xmax;
+    }
+    fn rollback_delete(
+        &mut self,
+        slot_id: u8,
+        transaction_with_next_lsn: Option<(&mut Transaction, u8)>,
+    ) {
+        if let Some((transaction, next_lsn)) = transaction_with_next_lsn {
+            let lsn = transaction.log_compensate_delete(self.page_id(), slot_id, next_lsn);
+            self.set_page_lsn(lsn);
+        }
+        self.bytes[Self::slot_offset(slot_id) + 2] = INVALID_TRANSACTION_ID;
     }
     fn rollback_insert(
         &mut self,
@@ -716,53 +2245,168 @@ impl Page {
     ) {
         if let Some((transaction, next_lsn)) = transaction_with_next_lsn {
             let lsn = transaction.log_compensate_insert(self.page_id(), slot_id, next_lsn);
-            self.bytes[1] = lsn;
-        }
-        self.bytes[2] -= 1;
-        for i in slot_id..self.tuple_length() {
-            self.bytes[i as usize + Self::HEADER_SIZE] =
-                self.bytes[i as usize + Self::HEADER_SIZE + 1];
+            self.set_page_lsn(lsn);
         }
+        // The slot stays, dead, so the rows after it keep their slot ids,
+        // which row locks and logical decoding refer to.
+        let offset = Self::slot_offset(slot_id);
+        self.bytes[offset] = 0;
+        self.bytes[offset + 1] = INVALID_TRANSACTION_ID;
     }
 }
 
+// xmin of a slot whose insert was rolled back, and xmax of a slot that is not
+// deleted. Every transaction logs its Begin before it writes, so with u8 lsns
+// no writing transaction gets this id.
+const INVALID_TRANSACTION_ID: u8 = u8::MAX;
+
+// Direct I/O is only implemented with Linux's O_DIRECT. Elsewhere pages go
+// through the OS page cache, as without direct I/O.
+#[cfg(target_os = "linux")]
+fn open_direct(options: &mut OpenOptions) -> bool {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.custom_flags(libc::O_DIRECT);
+    true
+}
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_options: &mut OpenOptions) -> bool {
+    false
+}
+const DIRECT_IO_BLOCK_SIZE: usize = 4096;
+
+// O_DIRECT requires the buffer, offset and length to be block aligned.
+#[repr(C, align(4096))]
+struct AlignedBlock([u8; DIRECT_IO_BLOCK_SIZE]);
+
 struct PageManager {
     file: File,
+    direct_io: bool,
+    // Includes the pages not synced yet.
+    file_length: u64,
+    // With direct I/O, the block holding the last page read or written, and
+    // whether it has writes that are not on disk yet.
+    block: Box<AlignedBlock>,
+    block_offset: Option<u64>,
+    is_block_dirty: bool,
 }
 
 impl PageManager {
-    fn init(file_name: &str) -> Self {
-        Self {
-            file: OpenOptions::new()
+    fn init(file_name: &str, direct_io: bool) -> Self {
+        Self::open(
+            OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
-                .truncate(true)
-                .open(file_name)
-                .unwrap(),
-        }
+                .truncate(true),
+            file_name,
+            direct_io,
+        )
     }
-    fn load(file_name: &str) -> Self {
+    fn load(file_name: &str, direct_io: bool) -> Self {
+        Self::open(
+            OpenOptions::new().read(true).write(true),
+            file_name,
+            direct_io,
+        )
+    }
+    fn open(options: &mut OpenOptions, file_name: &str, direct_io: bool) -> Self {
+        let direct_io = direct_io && open_direct(options);
+        let file = options.open(file_name).unwrap();
         Self {
-            file: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(file_name)
-                .unwrap(),
+            file_length: file.metadata().unwrap().len(),
+            file,
+            direct_io,
+            block: Box::new(AlignedBlock([0; DIRECT_IO_BLOCK_SIZE])),
+            block_offset: None,
+            is_block_dirty: false,
         }
     }
     fn write_page(&mut self, page: &Page) {
+        self.write_page_unsynced(page);
+        self.sync();
+    }
+    // The page is durable after the next sync. With direct I/O, the pages of
+    // a block are written together.
+    fn write_page_unsynced(&mut self, page: &Page) {
         let offset = page.page_id() as u64 * PAGE_SIZE as u64;
-        self.file.seek(SeekFrom::Start(offset)).unwrap();
-        self.file.write_all(&page.bytes).unwrap();
+        let mut bytes = page.bytes;
+        bytes[3] = page.compute_checksum();
+        if self.direct_io {
+            let start = self.load_block(offset);
+            self.block.0[start..start + PAGE_SIZE].copy_from_slice(&bytes);
+            self.is_block_dirty = true;
+        } else {
+            self.file.seek(SeekFrom::Start(offset)).unwrap();
+            self.file.write_all(&bytes).unwrap();
+        }
+        self.file_length = self.file_length.max(offset + PAGE_SIZE as u64);
+    }
+    fn sync(&mut self) {
+        self.write_block();
         self.file.sync_all().unwrap();
     }
-    fn read_page(&mut self, page_id: u8) -> Page {
+    // Returns where the page at offset starts in the cached block, after
+    // reading the block unless it is cached already.
+    fn load_block(&mut self, offset: u64) -> usize {
+        let block_offset = offset - offset % DIRECT_IO_BLOCK_SIZE as u64;
+        if self.block_offset != Some(block_offset) {
+            self.write_block();
+            self.file.seek(SeekFrom::Start(block_offset)).unwrap();
+            let read_size = self.file.read(&mut self.block.0).unwrap();
+            self.block.0[read_size..].fill(0);
+            self.block_offset = Some(block_offset);
+        }
+        (offset - block_offset) as usize
+    }
+    // O_DIRECT writes whole blocks, so the logical file length is restored
+    // afterwards, to keep the layout of the buffered mode.
+    fn write_block(&mut self) {
+        let Some(block_offset) = self.block_offset.filter(|_| self.is_block_dirty) else {
+            return;
+        };
+        self.file.seek(SeekFrom::Start(block_offset)).unwrap();
+        self.file.write_all(&self.block.0).unwrap();
+        self.file.set_len(self.file_length).unwrap();
+        self.is_block_dirty = false;
+    }
+    fn read_page(&mut self, page_id: u8) -> Result<Page, PageCorrupted> {
+        let bytes = self
+            .read_page_bytes(page_id)
+            .ok_or(PageCorrupted::PastEndOfFile { page_id })?;
+        let page = Page::load(bytes);
+        if page.checksum() != page.compute_checksum() {
+            return Err(PageCorrupted::ChecksumMismatch { page_id });
+        }
+        if page.format_version() != Page::FORMAT_VERSION {
+            return Err(PageCorrupted::UnsupportedFormatVersion {
+                page_id,
+                format_version: page.format_version(),
+            });
+        }
+        if page.page_id() != page_id {
+            return Err(PageCorrupted::PageIdMismatch {
+                page_id,
+                stored_page_id: page.page_id(),
+            });
+        }
+        Ok(page)
+    }
+    // The bytes stored at page_id's offset, as they are on disk.
+    fn read_page_bytes(&mut self, page_id: u8) -> Option<[u8; PAGE_SIZE]> {
         let offset = page_id as u64 * PAGE_SIZE as u64;
-        self.file.seek(SeekFrom::Start(offset)).unwrap();
         let mut bytes = [0; PAGE_SIZE];
-        self.file.read_exact(&mut bytes).unwrap();
-        Page::load(bytes)
+        if self.direct_io {
+            // Reads past the end are zero filled, so they have to be caught here.
+            if offset + PAGE_SIZE as u64 > self.file_length {
+                return None;
+            }
+            let start = self.load_block(offset);
+            bytes.copy_from_slice(&self.block.0[start..start + PAGE_SIZE]);
+        } else {
+            self.file.seek(SeekFrom::Start(offset)).unwrap();
+            self.file.read_exact(&mut bytes).ok()?;
+        }
+        Some(bytes)
     }
     fn allocate_page(&mut self) -> u8 {
         let page_id = self.next_page_id();
@@ -770,9 +2414,28 @@ impl PageManager {
         self.write_page(&page);
         page_id
     }
+    // Reads a page that another process may be writing at the same time. A
+    // half written page fails its checksum, so it is read again until the
+    // write has completed.
+    fn read_page_while_written(&mut self, page_id: u8) -> Page {
+        for _ in 0..100 {
+            let page = Page::load(self.read_page_bytes(page_id).unwrap());
+            if page.page_id() == page_id && page.checksum() == page.compute_checksum() {
+                return page;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("page {} keeps failing its checksum", page_id);
+    }
+    // Page allocation is not logged, so redo, e.g. after a restore or on a
+    // standby, can reach pages past the end of the data file.
+    fn allocate_pages_through(&mut self, page_id: u8) {
+        while self.file_length / PAGE_SIZE as u64 <= page_id as u64 {
+            self.allocate_page();
+        }
+    }
     fn next_page_id(&self) -> u8 {
-        let metadata = self.file.metadata().unwrap();
-        (metadata.len() / PAGE_SIZE as u64) as u8
+        (self.file_length / PAGE_SIZE as u64) as u8
     }
 }
 
@@ -782,6 +2445,10 @@ struct BufferPoolManager {
     frames: Vec<Frame>,
     page_frame_table: HashMap<u8, usize>,
     replacer: Replacer,
+    statistics: BufferPoolStatistics,
+    access_clock: u64,
+    // When set, a dirty page is only written after its log records are durable.
+    log_manager: Option<Arc<RwLock<LogManager>>>,
 }
 
 struct Frame {
@@ -789,6 +2456,26 @@ struct Frame {
     page_id: u8,
     pin_count: usize,
     is_dirty: bool,
+    last_access: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct BufferPoolStatistics {
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    dirty_writes: u64,
+    // hits on a page that was already pinned by someone else
+    shared_pin_hits: u64,
+}
+
+#[derive(Clone, Debug)]
+struct FrameSnapshot {
+    frame_id: usize,
+    page_id: u8,
+    pin_count: usize,
+    is_dirty: bool,
+    last_access: u64,
 }
 
 impl BufferPoolManager {
@@ -799,44 +2486,124 @@ impl BufferPoolManager {
             frames: Vec::with_capacity(max_frame_length),
             page_frame_table: HashMap::new(),
             replacer: Replacer::new(),
+            statistics: BufferPoolStatistics::default(),
+            access_clock: 0,
+            log_manager: None,
         }
     }
     fn read_page(&mut self, page_id: u8) -> Arc<RwLock<Page>> {
+        self.access_clock += 1;
         if let Some(frame_id) = self.page_frame_table.get(&page_id) {
             let frame = &mut self.frames[*frame_id];
+            self.statistics.hits += 1;
+            if frame.pin_count > 0 {
+                self.statistics.shared_pin_hits += 1;
+            }
             frame.pin_count += 1;
+            frame.last_access = self.access_clock;
             self.replacer.pin(*frame_id);
-            frame.page.clone()
-        } else if self.frames.len() < self.max_frame_length {
-            self.frames.push(Frame {
-                page: Arc::new(RwLock::new(self.page_manager.read_page(page_id))),
-                page_id,
-                pin_count: 1,
-                is_dirty: false,
-            });
-            let frame_id = self.frames.len() - 1;
-            self.page_frame_table.insert(page_id, frame_id);
-            self.replacer.pin(frame_id);
-            self.frames[frame_id].page.clone()
+            return frame.page.clone();
+        }
+        self.statistics.misses += 1;
+        // Recovery rebuilds the pages a crash left corrupted, so a page that
+        // fails here was damaged while the database was running.
+        let page = self
+            .page_manager
+            .read_page(page_id)
+            .unwrap_or_else(|error| panic!("{:?}", error));
+        let frame = Frame {
+            page: Arc::new(RwLock::new(page)),
+            page_id,
+            pin_count: 1,
+            is_dirty: false,
+            last_access: self.access_clock,
+        };
+        let frame_id = if self.frames.len() < self.max_frame_length {
+            self.frames.push(frame);
+            self.frames.len() - 1
         } else {
-            let victim_frame_id = self.replacer.victim();
-            if self.frames[victim_frame_id].is_dirty {
-                let page = self.frames[victim_frame_id].page.read().unwrap();
-                self.page_manager.write_page(&page);
-            }
-            self.page_frame_table
-                .remove(&self.frames[victim_frame_id].page_id);
-            self.frames[victim_frame_id] = Frame {
-                page: Arc::new(RwLock::new(self.page_manager.read_page(page_id))),
-                page_id,
-                pin_count: 1,
-                is_dirty: false,
-            };
-            self.page_frame_table.insert(page_id, victim_frame_id);
-            self.replacer.pin(victim_frame_id);
-            self.frames[victim_frame_id].page.clone()
+            let victim_frame_id = self.replacer.victim().unwrap();
+            self.evict(victim_frame_id);
+            self.frames[victim_frame_id] = frame;
+            victim_frame_id
+        };
+        self.page_frame_table.insert(page_id, frame_id);
+        self.replacer.pin(frame_id);
+        self.frames[frame_id].page.clone()
+    }
+    fn evict(&mut self, frame_id: usize) {
+        if self.frames[frame_id].is_dirty {
+            let page = self.frames[frame_id].page.read().unwrap();
+            if let Some(ref log_manager) = self.log_manager {
+                LogManager::flush_urgently(log_manager, page.page_lsn());
+            }
+            self.page_manager.write_page(&page);
+            self.statistics.dirty_writes += 1;
+        }
+        self.page_frame_table.remove(&self.frames[frame_id].page_id);
+        self.statistics.evictions += 1;
+    }
+    // The caller must make the log durable first.
+    fn flush_dirty_pages(&mut self) {
+        for frame in self.frames.iter_mut().filter(|frame| frame.pin_count == 0) {
+            let mut page = frame.page.write().unwrap();
+            if frame.is_dirty || page.rec_lsn.is_some() {
+                self.page_manager.write_page_unsynced(&page);
+                self.statistics.dirty_writes += 1;
+                page.rec_lsn = None;
+                frame.is_dirty = false;
+            }
+        }
+        self.page_manager.sync();
+    }
+    // Pinned pages are left out, their latches may be held.
+    fn dirty_page_ids(&self) -> Vec<u8> {
+        self.frames
+            .iter()
+            .filter(|frame| frame.pin_count == 0)
+            .filter(|frame| frame.is_dirty || frame.page.read().unwrap().rec_lsn.is_some())
+            .map(|frame| frame.page_id)
+            .collect()
+    }
+    // Writes page_id if it is still cached, unpinned and dirty.
+    fn flush_page(&mut self, page_id: u8) {
+        let Some(frame_id) = self.page_frame_table.get(&page_id) else {
+            return;
+        };
+        let frame = &mut self.frames[*frame_id];
+        if frame.pin_count > 0 {
+            return;
+        }
+        let mut page = frame.page.write().unwrap();
+        if frame.is_dirty || page.rec_lsn.is_some() {
+            if let Some(ref log_manager) = self.log_manager {
+                LogManager::flush_urgently(log_manager, page.page_lsn());
+            }
+            self.page_manager.write_page(&page);
+            self.statistics.dirty_writes += 1;
+            page.rec_lsn = None;
+            frame.is_dirty = false;
         }
     }
+    fn pages(&self) -> Vec<Arc<RwLock<Page>>> {
+        self.frames.iter().map(|frame| frame.page.clone()).collect()
+    }
+    fn statistics(&self) -> BufferPoolStatistics {
+        self.statistics
+    }
+    fn snapshot(&self) -> Vec<FrameSnapshot> {
+        self.frames
+            .iter()
+            .enumerate()
+            .map(|(frame_id, frame)| FrameSnapshot {
+                frame_id,
+                page_id: frame.page_id,
+                pin_count: frame.pin_count,
+                is_dirty: frame.is_dirty,
+                last_access: frame.last_access,
+            })
+            .collect()
+    }
     fn allocate_page(&mut self) -> Arc<RwLock<Page>> {
         let page_id = self.page_manager.allocate_page();
         self.read_page(page_id)
@@ -845,12 +2612,42 @@ impl BufferPoolManager {
         let frame_id = *self.page_frame_table.get(&page_id).unwrap();
         let frame = &mut self.frames[frame_id];
         frame.pin_count -= 1;
+        if is_dirty {
+            frame.is_dirty = true;
+        }
         if frame.pin_count == 0 {
             self.replacer.unpin(frame_id);
+            if self.frames.len() > self.max_frame_length {
+                self.shrink();
+            }
         }
-        if is_dirty {
-            self.frames[frame_id].is_dirty = true;
+    }
+    fn resize(&mut self, new_frame_length: usize) -> usize {
+        self.max_frame_length = new_frame_length;
+        if self.frames.len() > new_frame_length {
+            self.shrink();
+        } else {
+            self.frames.reserve(new_frame_length - self.frames.len());
+        }
+        self.frames.len()
+    }
+    // Evicts unpinned frames until the pool fits in max_frame_length.
+    // Pinned frames stay until their last unpin_page.
+    fn shrink(&mut self) {
+        while self.frames.len() > self.max_frame_length {
+            let Some(victim_frame_id) = self.replacer.victim() else {
+                break;
+            };
+            self.evict(victim_frame_id);
+            let last_frame_id = self.frames.len() - 1;
+            self.frames.swap_remove(victim_frame_id);
+            if victim_frame_id != last_frame_id {
+                self.page_frame_table
+                    .insert(self.frames[victim_frame_id].page_id, victim_frame_id);
+                self.replacer.rename(last_frame_id, victim_frame_id);
+            }
         }
+        self.frames.shrink_to(self.max_frame_length);
     }
 }
 
@@ -876,8 +2673,8 @@ impl Replacer {
             queue: VecDeque::new(),
         }
     }
-    fn victim(&mut self) -> usize {
-        self.queue.pop_front().unwrap()
+    fn victim(&mut self) -> Option<usize> {
+        self.queue.pop_front()
     }
     fn unpin(&mut self, frame_index: usize) {
         if let Some(index) = self.queue.iter().position(|&x| x == frame_index) {
@@ -890,263 +2687,3763 @@ impl Replacer {
             self.queue.remove(index);
         }
     }
+    fn rename(&mut self, from_frame_index: usize, to_frame_index: usize) {
+        if let Some(index) = self.queue.iter().position(|&x| x == from_frame_index) {
+            self.queue[index] = to_frame_index;
+        }
+    }
 }
 
-struct Database {
-    log_manager: Arc<RwLock<LogManager>>,
-    buffer_pool_manager: Arc<RwLock<BufferPoolManager>>,
-    lock_manager: Arc<LockManager>,
-    current_transaction_id: AtomicU8,
-    last_page_id: AtomicU8,
+enum SystemView {
+    BufferPoolStatistics,
+    BufferPoolFrames,
+    Locks,
+    LockWaits,
 }
 
-impl Database {
-    fn init(file_name: &str, log_file_name: &str, buffer_pool_max_frame_length: usize) -> Self {
-        let mut page_manager = PageManager::init(file_name);
-        page_manager.allocate_page();
-        Self {
-            log_manager: Arc::new(RwLock::new(LogManager::init(log_file_name))),
-            buffer_pool_manager: Arc::new(RwLock::new(BufferPoolManager::new(
-                page_manager,
-                buffer_pool_max_frame_length,
-            ))),
-            lock_manager: Arc::new(LockManager::new()),
-            current_transaction_id: AtomicU8::new(0),
-            last_page_id: AtomicU8::new(0),
-        }
-    }
-    fn load(file_name: &str, log_file_name: &str, buffer_pool_max_frame_length: usize) -> Self {
-        let log_manager = Arc::new(RwLock::new(LogManager::load(log_file_name)));
-        let page_manager = PageManager::load(file_name);
-        let last_page_id = page_manager.next_page_id() - 1;
-        let buffer_pool_manager = Arc::new(RwLock::new(BufferPoolManager::new(
-            page_manager,
-            buffer_pool_max_frame_length,
-        )));
-        let mut recovery_manager =
-            RecoveryManager::new(log_manager.clone(), buffer_pool_manager.clone());
-        let max_transaction_id = recovery_manager.run();
-        Self {
-            log_manager,
-            buffer_pool_manager,
-            lock_manager: Arc::new(LockManager::new()),
-            current_transaction_id: AtomicU8::new(max_transaction_id + 1),
-            last_page_id: AtomicU8::new(last_page_id),
-        }
-    }
-    fn begin(&self) -> Transaction {
-        let mut transaction = Transaction::new(
-            self.current_transaction_id.load(Ordering::Relaxed),
-            self.lock_manager.clone(),
-            self.log_manager.clone(),
-        );
-        transaction.log_begin();
-        self.current_transaction_id.fetch_add(1, Ordering::Relaxed);
-        transaction
-    }
-    fn commit(&self, transaction: &mut Transaction) {
-        transaction.commit();
-    }
-    fn abort(&self, transaction: &mut Transaction) {
-        let logs = transaction.logs.clone();
-        for (i, log) in logs.iter().rev().enumerate() {
-            match &log.log_type {
-                LogType::Insert(ref insert_log) => {
-                    let page = self
-                        .buffer_pool_manager
-                        .write()
-                        .unwrap()
-                        .read_page(insert_log.page_id);
-                    {
-                        let mut page = page.write().unwrap();
-                        page.rollback_insert(
-                            insert_log.slot_id,
-                            Some((transaction, logs[i + 1].lsn)),
-                        );
-                    }
-                    {
-                        let mut page = page.write().unwrap();
-                        page.rollback_insert(
-                            insert_log.slot_id,
-                            Some((transaction, logs[i + 1].lsn)),
-                        );
-                    }
-                    self.buffer_pool_manager
-                        .write()
-                        .unwrap()
-                        .unpin_page(insert_log.page_id, true);
-                }
-                LogType::CompensateInsert(_) => {}
-                LogType::Begin(_) => {}
-                LogType::Commit(_) => {}
-                LogType::Abort(_) => {}
+impl SystemView {
+    fn columns(&self) -> Vec<&'static str> {
+        match self {
+            SystemView::BufferPoolStatistics => {
+                vec![
+                    "hits",
+                    "misses",
+                    "evictions",
+                    "dirty_writes",
+                    "shared_pin_hits",
+                ]
+            }
+            SystemView::BufferPoolFrames => {
+                vec![
+                    "frame_id",
+                    "page_id",
+                    "pin_count",
+                    "is_dirty",
+                    "last_access",
+                ]
+            }
+            SystemView::Locks => {
+                vec![
+                    "resource",
+                    "lock_type",
+                    "transaction_id",
+                    "is_granted",
+                    "wait_time_ms",
+                ]
             }
+            SystemView::LockWaits => vec!["waiter", "holder"],
         }
-        transaction.abort();
     }
-    fn insert(&self, transaction: &mut Transaction, tuple: u8) {
-        let page_id = self.last_page_id.load(Ordering::Relaxed);
-        let page = self.buffer_pool_manager.write().unwrap().read_page(page_id);
-        {
-            let mut page = page.write().unwrap();
-            if page.has_space() {
-                page.insert_tuple(tuple, Some(transaction));
-            } else {
-                let new_page = self.buffer_pool_manager.write().unwrap().allocate_page();
-                let new_page_id = {
-                    let mut new_page = new_page.write().unwrap();
-                    let new_page_id = new_page.page_id();
-                    new_page.insert_tuple(tuple, Some(transaction));
-                    new_page_id
-                };
-                self.buffer_pool_manager
-                    .write()
-                    .unwrap()
-                    .unpin_page(new_page_id, true);
-                self.last_page_id.store(new_page_id, Ordering::Relaxed);
+}
+
+// Where Database::restore takes the database from and how far it replays.
+struct RestoreOptions<'a> {
+    base_backup_dir_name: &'a str,
+    archive_dir_name: &'a str,
+    target: RecoveryTarget,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum RestoreError {
+    // The archive does not have this record, which is needed to make the base
+    // backup consistent.
+    MissingArchive { lsn: u8 },
+    // The target stops replay before the end of the base backup, whose pages
+    // may hold changes from up to end_lsn.
+    TargetBeforeBackupEnd { end_lsn: u8 },
+}
+
+enum RecoveryTarget {
+    Lsn(u8),
+    TransactionId(u8),
+    // milliseconds since the unix epoch
+    Timestamp(u64),
+}
+
+impl RecoveryTarget {
+    fn stops_before(&self, log: &Log) -> bool {
+        match (self, &log.log_type) {
+            (RecoveryTarget::Lsn(lsn), _) => log.lsn > *lsn,
+            (RecoveryTarget::Timestamp(timestamp), LogType::Commit(commit_log)) => {
+                commit_log.timestamp > *timestamp
             }
+            _ => false,
         }
-        self.buffer_pool_manager
-            .write()
-            .unwrap()
-            .unpin_page(page_id, true);
     }
-    fn read_all(&self, transaction: &mut Transaction) -> Vec<u8> {
-        let mut values = Vec::new();
-        let mut page_id = 0;
-        loop {
-            let page = self.buffer_pool_manager.write().unwrap().read_page(page_id);
-            {
-                let page = page.read().unwrap();
-                values.extend(page.read_tuples(transaction));
+    fn stops_after(&self, log: &Log) -> bool {
+        match (self, &log.log_type) {
+            (RecoveryTarget::TransactionId(transaction_id), LogType::Commit(commit_log)) => {
+                commit_log.transaction_id == *transaction_id
             }
-            self.buffer_pool_manager
-                .write()
-                .unwrap()
-                .unpin_page(page_id, false);
-            if self.last_page_id.load(Ordering::Relaxed) > page_id {
-                page_id += 1;
-            } else {
-                break;
+            (RecoveryTarget::TransactionId(transaction_id), LogType::Abort(abort_log)) => {
+                abort_log.transaction_id == *transaction_id
             }
+            _ => false,
         }
-        values
     }
 }
 
-impl Debug for Database {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "{:?}", self.buffer_pool_manager)?;
-        Ok(())
+// Replaying from start_lsn to at least end_lsn makes the copied pages consistent.
+struct BackupLabel {
+    start_lsn: u8,
+    end_lsn: u8,
+    // start_lsn of the backup an incremental backup builds on
+    incremental_from: Option<u8>,
+}
+
+impl BackupLabel {
+    const FILE_NAME: &'static str = "backup_label";
+    const DATA_FILE_NAME: &'static str = "data";
+    const LOG_DIR_NAME: &'static str = "log";
+
+    fn write(&self, dir_name: &str) {
+        let mut file = File::create(Path::new(dir_name).join(Self::FILE_NAME)).unwrap();
+        writeln!(file, "start_lsn: {}", self.start_lsn).unwrap();
+        writeln!(file, "end_lsn: {}", self.end_lsn).unwrap();
+        if let Some(incremental_from) = self.incremental_from {
+            writeln!(file, "incremental_from: {}", incremental_from).unwrap();
+        }
+        file.sync_all().unwrap();
+    }
+    // A backup from another process keeps the log from its start_lsn on with
+    // <log dir>/<backup dir>.backup until it has copied the log, like a slot.
+    // One left behind by a crashed backup has to be removed by hand.
+    fn start_path(log_dir_name: &str, backup_dir_name: &str) -> PathBuf {
+        Path::new(log_dir_name).join(format!("{}.backup", backup_dir_name.replace('/', "_")))
+    }
+    fn write_start(log_dir_name: &str, backup_dir_name: &str, start_lsn: u8) {
+        let path = BackupLabel::start_path(log_dir_name, backup_dir_name);
+        let temporary_path = path.with_extension("backup.tmp");
+        let mut file = File::create(&temporary_path).unwrap();
+        writeln!(file, "start_lsn: {}", start_lsn).unwrap();
+        file.sync_all().unwrap();
+        fs::rename(temporary_path, path).unwrap();
+    }
+    // start_lsn of every backup in progress
+    fn start_lsns(log_dir_name: &str) -> Vec<u8> {
+        fs::read_dir(log_dir_name)
+            .unwrap()
+            .filter_map(|entry| {
+                let entry = entry.unwrap();
+                entry.file_name().to_str()?.strip_suffix(".backup")?;
+                let text = fs::read_to_string(entry.path()).ok()?;
+                text.lines()
+                    .find_map(|line| line.strip_prefix("start_lsn: "))
+                    .map(|value| value.parse().unwrap())
+            })
+            .collect()
+    }
+    fn read(dir_name: &str) -> Self {
+        let text = fs::read_to_string(Path::new(dir_name).join(Self::FILE_NAME)).unwrap();
+        let value = |key: &str| -> Option<u8> {
+            text.lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix(": "))
+                .map(|value| value.parse().unwrap())
+        };
+        Self {
+            start_lsn: value("start_lsn").unwrap(),
+            end_lsn: value("end_lsn").unwrap(),
+            incremental_from: value("incremental_from"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CheckpointError {
+    // Fewer than the 2 lsns a checkpoint takes are left.
+    LsnsExhausted,
+    // The active transactions and dirty pages do not fit the u8 length of an
+    // EndCheckpoint record.
+    RecordTooLarge,
+}
+
+#[derive(Clone)]
+struct Checkpointer {
+    log_manager: Arc<RwLock<LogManager>>,
+    buffer_pool_manager: Arc<RwLock<BufferPoolManager>>,
+}
+
+impl Checkpointer {
+    fn new(
+        log_manager: Arc<RwLock<LogManager>>,
+        buffer_pool_manager: Arc<RwLock<BufferPoolManager>>,
+    ) -> Self {
+        Self {
+            log_manager,
+            buffer_pool_manager,
+        }
+    }
+    // Returns the lsn recovery has to start from for pages on disk now.
+    fn checkpoint(&self) -> Result<u8, CheckpointError> {
+        let begin_checkpoint_lsn = {
+            let mut log_manager = self.log_manager.write().unwrap();
+            if log_manager.remaining_lsns() < 2 {
+                return Err(CheckpointError::LsnsExhausted);
+            }
+            log_manager.append(LogType::BeginCheckpoint).lsn
+        };
+        // Pages are written one at a time, so transactions keep running while
+        // the pages that were dirty at the start of the checkpoint are flushed.
+        let dirty_page_ids = self.buffer_pool_manager.read().unwrap().dirty_page_ids();
+        LogManager::flush_all(&self.log_manager);
+        for page_id in dirty_page_ids {
+            self.buffer_pool_manager
+                .write()
+                .unwrap()
+                .flush_page(page_id);
+        }
+        let pages = self.buffer_pool_manager.read().unwrap().pages();
+        let dirty_page_table: Vec<(u8, u8)> = pages
+            .iter()
+            .filter_map(|page| {
+                let page = page.read().unwrap();
+                page.rec_lsn.map(|rec_lsn| (page.page_id(), rec_lsn))
+            })
+            .collect();
+        let end_checkpoint_log = {
+            let mut log_manager = self.log_manager.write().unwrap();
+            let end_checkpoint_log = EndCheckpointLog {
+                begin_checkpoint_lsn,
+                max_transaction_id: log_manager.max_transaction_id,
+                transaction_table: log_manager
+                    .transaction_table
+                    .iter()
+                    .map(|(transaction_id, entry)| (*transaction_id, *entry))
+                    .collect(),
+                dirty_page_table,
+            };
+            // Recovery ignores a BeginCheckpoint without its EndCheckpoint.
+            let log = Log {
+                lsn: begin_checkpoint_lsn,
+                log_type: LogType::EndCheckpoint(end_checkpoint_log.clone()),
+            };
+            if log.try_serialize().is_none() {
+                return Err(CheckpointError::RecordTooLarge);
+            }
+            log_manager.append(log.log_type);
+            end_checkpoint_log
+        };
+        LogManager::flush_all(&self.log_manager);
+
+        let min_recovery_lsn = end_checkpoint_log
+            .dirty_page_table
+            .iter()
+            .map(|(_, rec_lsn)| *rec_lsn)
+            .chain(
+                end_checkpoint_log
+                    .transaction_table
+                    .iter()
+                    .map(|(_, entry)| entry.first_lsn),
+            )
+            .fold(begin_checkpoint_lsn, u8::min);
+        self.log_manager.read().unwrap().truncate(min_recovery_lsn);
+        Ok(min_recovery_lsn)
+    }
+    // The thread stops at the first failed checkpoint and returns its error.
+    fn spawn(self, interval: Duration) -> CheckpointerHandle {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                // Nothing to do, and an idle database would otherwise use up its
+                // lsns on checkpoint records alone.
+                if self
+                    .log_manager
+                    .read()
+                    .unwrap()
+                    .last_checkpoint_lsn
+                    .is_some()
+                {
+                    continue;
+                }
+                self.checkpoint()?;
+            }
+            Ok(())
+        });
+        CheckpointerHandle { sender, handle }
+    }
+}
+
+struct CheckpointerHandle {
+    sender: Sender<()>,
+    handle: JoinHandle<Result<(), CheckpointError>>,
+}
+
+impl CheckpointerHandle {
+    // Returns the error the checkpointer stopped at, if it did.
+    fn stop(self) -> Result<(), CheckpointError> {
+        drop(self.sender);
+        self.handle.join().unwrap()
+    }
+}
+
+// Durable records from next_lsn on, first those already in the log directory,
+// then every batch GroupCommit writes. Ends when the database is dropped. If
+// the log no longer has next_lsn, the stream continues from the first lsn it
+// has, and the consumer sees the jump.
+struct LogStream {
+    // None once the record with the last possible lsn has been returned.
+    next_lsn: Option<u8>,
+    logs: std::iter::Chain<LogReader, std::iter::Flatten<mpsc::IntoIter<Vec<Log>>>>,
+}
+
+impl LogStream {
+    fn subscribe(group_commit: &GroupCommit, dir_name: &str, next_lsn: u8) -> Self {
+        // Subscribe before reading the directory, so a batch is either in the
+        // files read below or arrives through the channel, possibly both.
+        let (sender, receiver) = mpsc::channel();
+        group_commit.subscribers.lock().unwrap().push(sender);
+        Self {
+            next_lsn: Some(next_lsn),
+            logs: LogReader::open(dir_name).chain(receiver.into_iter().flatten()),
+        }
+    }
+}
+
+impl Iterator for LogStream {
+    type Item = Log;
+
+    fn next(&mut self) -> Option<Log> {
+        loop {
+            let next_lsn = self.next_lsn?;
+            let log = self.logs.next()?;
+            if log.lsn < next_lsn {
+                continue;
+            }
+            self.next_lsn = log.lsn.checked_add(1);
+            return Some(log);
+        }
+    }
+}
+
+// Streams durable log records to standbys. A standby connects, sends the lsn
+// it needs next and then receives every record from there on.
+struct WalSender {
+    group_commit: Arc<GroupCommit>,
+    sync_replication: Arc<SyncReplication>,
+    dir_name: String,
+}
+
+impl WalSender {
+    fn new(log_manager: &RwLock<LogManager>) -> Self {
+        let log_manager = log_manager.read().unwrap();
+        Self {
+            group_commit: log_manager.group_commit.clone(),
+            sync_replication: log_manager.sync_replication.clone(),
+            dir_name: log_manager.dir_name.clone(),
+        }
+    }
+    fn spawn(self, listener: TcpListener) -> WalSenderHandle {
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if receiver.try_recv() != Err(TryRecvError::Empty) {
+                    return;
+                }
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let wal_sender = WalSender {
+                    group_commit: self.group_commit.clone(),
+                    sync_replication: self.sync_replication.clone(),
+                    dir_name: self.dir_name.clone(),
+                };
+                thread::spawn(move || wal_sender.stream(stream));
+            }
+        });
+        WalSenderHandle {
+            address,
+            sender,
+            handle,
+        }
+    }
+    fn stream(self, mut stream: TcpStream) {
+        let mut next_lsn = [0];
+        if stream.read_exact(&mut next_lsn).is_err() {
+            return;
+        }
+        let next_lsn = next_lsn[0];
+        // The standby acks every lsn it has flushed.
+        let standby_id = self.sync_replication.register();
+        let mut acks = stream.try_clone().unwrap();
+        let sync_replication = self.sync_replication.clone();
+        thread::spawn(move || {
+            let mut lsn = [0];
+            while acks.read_exact(&mut lsn).is_ok() {
+                sync_replication.ack(standby_id, lsn[0]);
+            }
+            sync_replication.disconnect(standby_id);
+        });
+        let logs = LogStream::subscribe(&self.group_commit, &self.dir_name, next_lsn);
+        drop(self.group_commit);
+        for log in logs {
+            if stream.write_all(&log.serialize()).is_err() {
+                break;
+            }
+        }
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+struct WalSenderHandle {
+    address: SocketAddr,
+    sender: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl WalSenderHandle {
+    fn stop(self) {
+        drop(self.sender);
+        // Wakes the listener up so it sees the closed channel.
+        let _ = TcpStream::connect(self.address);
+        self.handle.join().unwrap();
+    }
+}
+
+// Slots of the inserts and deletes of every transaction that has neither
+// committed nor aborted on the primary.
+#[derive(Default)]
+struct InFlightChanges {
+    inserts: HashMap<u8, Vec<(u8, u8)>>,
+    deletes: HashMap<u8, Vec<(u8, u8)>>,
+}
+
+impl InFlightChanges {
+    fn apply(&mut self, log: &Log) {
+        match log.log_type {
+            LogType::Insert(ref insert_log) => {
+                self.inserts
+                    .entry(insert_log.transaction_id)
+                    .or_default()
+                    .push((insert_log.page_id, insert_log.slot_id));
+            }
+            LogType::CompensateInsert(ref compensate_insert_log) => {
+                let page_id = compensate_insert_log.page_id;
+                let slot_id = compensate_insert_log.slot_id;
+                if let Some(slots) = self.inserts.get_mut(&compensate_insert_log.transaction_id) {
+                    slots.retain(|slot| *slot != (page_id, slot_id));
+                }
+            }
+            LogType::Delete(ref delete_log) => {
+                self.deletes
+                    .entry(delete_log.transaction_id)
+                    .or_default()
+                    .push((delete_log.page_id, delete_log.slot_id));
+            }
+            LogType::CompensateDelete(ref compensate_delete_log) => {
+                let page_id = compensate_delete_log.page_id;
+                let slot_id = compensate_delete_log.slot_id;
+                if let Some(slots) = self.deletes.get_mut(&compensate_delete_log.transaction_id) {
+                    slots.retain(|slot| *slot != (page_id, slot_id));
+                }
+            }
+            LogType::Commit(CommitLog { transaction_id, .. })
+            | LogType::Abort(AbortLog { transaction_id }) => {
+                self.inserts.remove(&transaction_id);
+                self.deletes.remove(&transaction_id);
+            }
+            _ => {}
+        }
+    }
+    fn inserted_slots(&self) -> HashSet<(u8, u8)> {
+        self.inserts.values().flatten().copied().collect()
+    }
+    fn deleted_slots(&self) -> HashSet<(u8, u8)> {
+        self.deletes.values().flatten().copied().collect()
+    }
+}
+
+const DEFAULT_MAX_STANDBY_DELAY: Duration = Duration::from_secs(30);
+
+// Shared by the wal receiver of a standby and its read-only transactions.
+// Replaying a page change waits until no transaction is reading, for at most
+// max_standby_delay, and then cancels the ones still reading. So each one
+// sees the pages as of the lsn it started at, or fails with ReplayConflict.
+struct HotStandby {
+    state: Mutex<HotStandbyState>,
+    condvar: Condvar,
+}
+
+struct HotStandbyState {
+    // the canceled flag of every open read-only transaction
+    readers: Vec<Arc<AtomicBool>>,
+    max_standby_delay: Duration,
+    replayed_lsn: Option<u8>,
+    in_flight_changes: InFlightChanges,
+    // Why the standby stopped receiving the primary's log, if it did.
+    replication_error: Option<ReplicationError>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ReplicationError {
+    // The primary sent a record that does not continue the standby's log, e.g.
+    // because it had removed the lsns the standby needs.
+    LogDiverged {
+        expected_lsn: Option<u8>,
+        received_lsn: u8,
+    },
+}
+
+impl HotStandbyState {
+    fn cancel_readers(&mut self) {
+        for canceled in self.readers.drain(..) {
+            canceled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl HotStandby {
+    fn snapshot(hot_standby: &Arc<HotStandby>) -> StandbySnapshot {
+        let mut state = hot_standby.state.lock().unwrap();
+        let canceled = Arc::new(AtomicBool::new(false));
+        state.readers.push(canceled.clone());
+        StandbySnapshot {
+            hot_standby: hot_standby.clone(),
+            lsn: state.replayed_lsn,
+            invisible_slots: state.in_flight_changes.inserted_slots(),
+            undeleted_slots: state.in_flight_changes.deleted_slots(),
+            canceled,
+        }
+    }
+    fn wait_for_readers<'a>(
+        &self,
+        state: MutexGuard<'a, HotStandbyState>,
+    ) -> MutexGuard<'a, HotStandbyState> {
+        let max_standby_delay = state.max_standby_delay;
+        let (mut state, _) = self
+            .condvar
+            .wait_timeout_while(state, max_standby_delay, |state| !state.readers.is_empty())
+            .unwrap();
+        state.cancel_readers();
+        state
+    }
+    fn wait_for_replay(&self, lsn: u8) {
+        let _state = self
+            .condvar
+            .wait_while(self.state.lock().unwrap(), |state| {
+                state.replayed_lsn < Some(lsn)
+            })
+            .unwrap();
+    }
+}
+
+struct StandbySnapshot {
+    hot_standby: Arc<HotStandby>,
+    lsn: Option<u8>,
+    invisible_slots: HashSet<(u8, u8)>,
+    // Deleted on the primary by transactions not committed at lsn.
+    undeleted_slots: HashSet<(u8, u8)>,
+    // Set by replay before it changes a page this transaction may read.
+    canceled: Arc<AtomicBool>,
+}
+
+impl Drop for StandbySnapshot {
+    fn drop(&mut self) {
+        self.hot_standby
+            .state
+            .lock()
+            .unwrap()
+            .readers
+            .retain(|canceled| !Arc::ptr_eq(canceled, &self.canceled));
+        self.hot_standby.condvar.notify_all();
+    }
+}
+
+// Runs on a standby: appends every record from the primary to the local log
+// and applies it to the pages with the redo logic of recovery.
+#[derive(Clone)]
+struct WalReceiver {
+    log_manager: Arc<RwLock<LogManager>>,
+    buffer_pool_manager: Arc<RwLock<BufferPoolManager>>,
+    hot_standby: Arc<HotStandby>,
+    last_page_id: Arc<AtomicU8>,
+}
+
+impl WalReceiver {
+    fn spawn(self, mut stream: TcpStream) -> JoinHandle<()> {
+        thread::spawn(move || {
+            // Replay has its own thread, so waiting for readers never holds
+            // back the flushes and acks.
+            let (sender, receiver) = mpsc::channel();
+            let wal_receiver = self.clone();
+            let replay_handle = thread::spawn(move || {
+                for log in receiver {
+                    wal_receiver.replay(&log);
+                }
+            });
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while let Some(log) = Log::read_from(&mut reader) {
+                let lsn = log.lsn;
+                if let Err(error) = self.append(&log) {
+                    self.hot_standby.state.lock().unwrap().replication_error = Some(error);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    break;
+                }
+                sender.send(log).unwrap();
+                // Records that arrived together are flushed with one fsync,
+                // and the ack covers all of them.
+                if reader.buffer().is_empty() {
+                    LogManager::flush(&self.log_manager, lsn);
+                    if stream.write_all(&[lsn]).is_err() {
+                        break;
+                    }
+                }
+            }
+            LogManager::flush_all(&self.log_manager);
+            drop(sender);
+            replay_handle.join().unwrap();
+        })
+    }
+    fn append(&self, log: &Log) -> Result<(), ReplicationError> {
+        if let LogType::EndCheckpoint(_) = log.log_type {
+            // The primary has written everything before its checkpoint, so the
+            // standby does the same before recovery may start from there.
+            self.hot_standby.wait_for_replay(log.lsn - 1);
+            LogManager::flush_all(&self.log_manager);
+            self.buffer_pool_manager
+                .write()
+                .unwrap()
+                .flush_dirty_pages();
+        }
+        let mut log_manager = self.log_manager.write().unwrap();
+        if log_manager.current_lsn != Some(log.lsn) {
+            return Err(ReplicationError::LogDiverged {
+                expected_lsn: log_manager.current_lsn,
+                received_lsn: log.lsn,
+            });
+        }
+        log_manager.append(log.log_type.clone());
+        Ok(())
+    }
+    fn replay(&self, log: &Log) {
+        let mut state = self.hot_standby.state.lock().unwrap();
+        if let Some(page_id) = log.log_type.page_id() {
+            state = self.hot_standby.wait_for_readers(state);
+            // Evicting a page flushes the log up to its page_lsn first.
+            RecoveryManager::redo_log(&self.buffer_pool_manager, log);
+            self.last_page_id.fetch_max(page_id, Ordering::Relaxed);
+        }
+        state.in_flight_changes.apply(log);
+        state.replayed_lsn = Some(log.lsn);
+        self.hot_standby.condvar.notify_all();
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Change {
+    Insert { page_id: u8, slot_id: u8, tuple: u8 },
+    Delete { page_id: u8, slot_id: u8 },
+}
+
+#[derive(Clone, Debug)]
+struct CommittedTransaction {
+    transaction_id: u8,
+    commit_lsn: u8,
+    // milliseconds since the unix epoch
+    timestamp: u64,
+    changes: Vec<Change>,
+    // where to resume once this transaction has been consumed
+    position: LogicalPosition,
+}
+
+// Where a logical decoding consumer is in the log. Decoding restarts at
+// restart_lsn, the first record of the oldest transaction still open, and
+// skips the transactions committed at or before confirmed_lsn.
+// Positions are kept in <log dir>/<slot name>.slot, and checkpoints keep the
+// log from restart_lsn on.
+#[derive(Clone, Copy, Debug, Default)]
+struct LogicalPosition {
+    restart_lsn: u8,
+    confirmed_lsn: Option<u8>,
+}
+
+impl LogicalPosition {
+    fn slot_path(dir_name: &str, slot_name: &str) -> PathBuf {
+        Path::new(dir_name).join(format!("{}.slot", slot_name))
+    }
+    fn read(dir_name: &str, slot_name: &str) -> Option<Self> {
+        let text = fs::read_to_string(LogicalPosition::slot_path(dir_name, slot_name)).ok()?;
+        let value = |key: &str| -> Option<u8> {
+            text.lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix(": "))
+                .map(|value| value.parse().unwrap())
+        };
+        Some(Self {
+            restart_lsn: value("restart_lsn").unwrap(),
+            confirmed_lsn: value("confirmed_lsn"),
+        })
+    }
+    fn write(&self, dir_name: &str, slot_name: &str) {
+        let path = LogicalPosition::slot_path(dir_name, slot_name);
+        let temporary_path = path.with_extension("tmp");
+        let mut file = File::create(&temporary_path).unwrap();
+        writeln!(file, "restart_lsn: {}", self.restart_lsn).unwrap();
+        if let Some(confirmed_lsn) = self.confirmed_lsn {
+            writeln!(file, "confirmed_lsn: {}", confirmed_lsn).unwrap();
+        }
+        file.sync_all().unwrap();
+        fs::rename(temporary_path, path).unwrap();
+    }
+    // restart_lsn of every slot in the log directory
+    fn restart_lsns(dir_name: &str) -> Vec<u8> {
+        fs::read_dir(dir_name)
+            .unwrap()
+            .filter_map(|entry| {
+                let file_name = entry.unwrap().file_name();
+                let slot_name = file_name.to_str()?.strip_suffix(".slot")?.to_string();
+                Some(LogicalPosition::read(dir_name, &slot_name)?.restart_lsn)
+            })
+            .collect()
+    }
+}
+
+// Turns physical log records into the committed transactions they belong to,
+// in commit order. Changes are buffered per transaction until its commit, and
+// dropped on abort.
+struct LogicalDecoder {
+    logs: Box<dyn Iterator<Item = Log> + Send>,
+    confirmed_lsn: Option<u8>,
+    // tx_id -> first lsn and changes so far
+    open_transactions: HashMap<u8, (u8, Vec<Change>)>,
+}
+
+impl LogicalDecoder {
+    fn new(
+        dir_name: &str,
+        logs: impl Iterator<Item = Log> + Send + 'static,
+        position: LogicalPosition,
+    ) -> Result<Self, LogicalDecodingError> {
+        // Segments are named by their first lsn.
+        if LogManager::segment_lsns(dir_name)
+            .first()
+            .is_some_and(|segment_lsn| *segment_lsn > position.restart_lsn)
+        {
+            return Err(LogicalDecodingError::RestartLsnRemoved {
+                restart_lsn: position.restart_lsn,
+            });
+        }
+        Ok(Self {
+            logs: Box::new(logs.skip_while(move |log| log.lsn < position.restart_lsn)),
+            confirmed_lsn: position.confirmed_lsn,
+            open_transactions: HashMap::new(),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LogicalDecodingError {
+    // The log was truncated past the slot's restart_lsn, e.g. because the slot
+    // file was created after the truncation.
+    RestartLsnRemoved { restart_lsn: u8 },
+}
+
+impl Iterator for LogicalDecoder {
+    type Item = CommittedTransaction;
+
+    fn next(&mut self) -> Option<CommittedTransaction> {
+        loop {
+            let log = self.logs.next()?;
+            match log.log_type {
+                LogType::Begin(BeginLog { transaction_id }) => {
+                    self.open_transactions
+                        .insert(transaction_id, (log.lsn, Vec::new()));
+                }
+                // Changes of a transaction whose Begin is before the stream
+                // are ignored, it would only be decoded in part.
+                LogType::Insert(ref insert_log) => {
+                    if let Some((_, changes)) =
+                        self.open_transactions.get_mut(&insert_log.transaction_id)
+                    {
+                        changes.push(Change::Insert {
+                            page_id: insert_log.page_id,
+                            slot_id: insert_log.slot_id,
+                            tuple: insert_log.tuple,
+                        });
+                    }
+                }
+                LogType::CompensateInsert(ref compensate_insert_log) => {
+                    if let Some((_, changes)) = self
+                        .open_transactions
+                        .get_mut(&compensate_insert_log.transaction_id)
+                    {
+                        if let Some(i) = changes.iter().rposition(|change| {
+                            matches!(change, Change::Insert { page_id, slot_id, .. }
+                                if *page_id == compensate_insert_log.page_id
+                                    && *slot_id == compensate_insert_log.slot_id)
+                        }) {
+                            changes.remove(i);
+                        }
+                    }
+                }
+                LogType::Delete(ref delete_log) => {
+                    if let Some((_, changes)) =
+                        self.open_transactions.get_mut(&delete_log.transaction_id)
+                    {
+                        changes.push(Change::Delete {
+                            page_id: delete_log.page_id,
+                            slot_id: delete_log.slot_id,
+                        });
+                    }
+                }
+                LogType::CompensateDelete(ref compensate_delete_log) => {
+                    if let Some((_, changes)) = self
+                        .open_transactions
+                        .get_mut(&compensate_delete_log.transaction_id)
+                    {
+                        if let Some(i) = changes.iter().rposition(|change| {
+                            matches!(change, Change::Delete { page_id, slot_id }
+                                if *page_id == compensate_delete_log.page_id
+                                    && *slot_id == compensate_delete_log.slot_id)
+                        }) {
+                            changes.remove(i);
+                        }
+                    }
+                }
+                LogType::Abort(AbortLog { transaction_id }) => {
+                    self.open_transactions.remove(&transaction_id);
+                }
+                LogType::Commit(ref commit_log) => {
+                    let changes = self
+                        .open_transactions
+                        .remove(&commit_log.transaction_id)
+                        .map_or(Vec::new(), |(_, changes)| changes);
+                    if changes.is_empty()
+                        || self
+                            .confirmed_lsn
+                            .is_some_and(|confirmed_lsn| log.lsn <= confirmed_lsn)
+                    {
+                        continue;
+                    }
+                    let restart_lsn = self
+                        .open_transactions
+                        .values()
+                        .map(|(first_lsn, _)| *first_lsn)
+                        .min()
+                        // The commit record itself is at confirmed_lsn and
+                        // has no changes, so restarting from it is safe.
+                        .unwrap_or(log.lsn);
+                    return Some(CommittedTransaction {
+                        transaction_id: commit_log.transaction_id,
+                        commit_lsn: log.lsn,
+                        timestamp: commit_log.timestamp,
+                        changes,
+                        position: LogicalPosition {
+                            restart_lsn,
+                            confirmed_lsn: Some(log.lsn),
+                        },
+                    });
+                }
+                LogType::BeginCheckpoint | LogType::EndCheckpoint(_) => {}
+            }
+        }
+    }
+}
+
+impl CommittedTransaction {
+    fn to_json(&self) -> String {
+        let changes: Vec<String> = self
+            .changes
+            .iter()
+            .map(|change| match change {
+                Change::Insert {
+                    page_id,
+                    slot_id,
+                    tuple,
+                } => format!(
+                    "{{\"type\":\"insert\",\"page_id\":{},\"slot_id\":{},\"tuple\":{}}}",
+                    page_id, slot_id, tuple
+                ),
+                Change::Delete { page_id, slot_id } => format!(
+                    "{{\"type\":\"delete\",\"page_id\":{},\"slot_id\":{}}}",
+                    page_id, slot_id
+                ),
+            })
+            .collect();
+        format!(
+            "{{\"transaction_id\":{},\"commit_lsn\":{},\"timestamp\":{},\"changes\":[{}]}}",
+            self.transaction_id,
+            self.commit_lsn,
+            self.timestamp,
+            changes.join(",")
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct DatabaseOptions {
+    buffer_pool_max_frame_length: usize,
+    // Page I/O bypasses the OS page cache.
+    direct_io: bool,
+}
+
+impl DatabaseOptions {
+    fn new(buffer_pool_max_frame_length: usize) -> Self {
+        Self {
+            buffer_pool_max_frame_length,
+            direct_io: false,
+        }
+    }
+    fn direct_io(mut self) -> Self {
+        self.direct_io = true;
+        self
+    }
+}
+
+struct Database {
+    log_manager: Arc<RwLock<LogManager>>,
+    buffer_pool_manager: Arc<RwLock<BufferPoolManager>>,
+    lock_manager: Arc<LockManager>,
+    commit_status: Arc<CommitStatusTable>,
+    current_transaction_id: AtomicU8,
+    last_page_id: Arc<AtomicU8>,
+    checkpointer: Mutex<Option<CheckpointerHandle>>,
+    wal_sender: Mutex<Option<WalSenderHandle>>,
+    // Set while the database is a standby.
+    wal_receiver: Mutex<Option<(TcpStream, JoinHandle<()>)>>,
+    hot_standby: Option<Arc<HotStandby>>,
+    // Pages recovery found corrupted and rebuilt from the log.
+    corrupted_pages: Mutex<Vec<PageCorrupted>>,
+    default_isolation_level: Mutex<IsolationLevel>,
+}
+
+impl Database {
+    fn init(file_name: &str, log_file_name: &str, buffer_pool_max_frame_length: usize) -> Self {
+        Database::init_with_options(
+            file_name,
+            log_file_name,
+            DatabaseOptions::new(buffer_pool_max_frame_length),
+        )
+    }
+    fn init_with_options(file_name: &str, log_file_name: &str, options: DatabaseOptions) -> Self {
+        let mut page_manager = PageManager::init(file_name, options.direct_io);
+        page_manager.allocate_page();
+        let log_manager = Arc::new(RwLock::new(LogManager::init(log_file_name)));
+        let mut buffer_pool_manager =
+            BufferPoolManager::new(page_manager, options.buffer_pool_max_frame_length);
+        buffer_pool_manager.log_manager = Some(log_manager.clone());
+        Self {
+            log_manager,
+            buffer_pool_manager: Arc::new(RwLock::new(buffer_pool_manager)),
+            lock_manager: Arc::new(LockManager::new()),
+            commit_status: Arc::new(CommitStatusTable::default()),
+            current_transaction_id: AtomicU8::new(0),
+            last_page_id: Arc::new(AtomicU8::new(0)),
+            checkpointer: Mutex::new(None),
+            wal_sender: Mutex::new(None),
+            wal_receiver: Mutex::new(None),
+            hot_standby: None,
+            corrupted_pages: Mutex::new(Vec::new()),
+            default_isolation_level: Mutex::new(IsolationLevel::Snapshot),
+        }
+    }
+    fn load(file_name: &str, log_file_name: &str, buffer_pool_max_frame_length: usize) -> Self {
+        Database::load_with_options(
+            file_name,
+            log_file_name,
+            DatabaseOptions::new(buffer_pool_max_frame_length),
+        )
+    }
+    fn load_with_options(file_name: &str, log_file_name: &str, options: DatabaseOptions) -> Self {
+        let log_manager = Arc::new(RwLock::new(LogManager::load(log_file_name)));
+        let page_manager = PageManager::load(file_name, options.direct_io);
+        let mut buffer_pool_manager =
+            BufferPoolManager::new(page_manager, options.buffer_pool_max_frame_length);
+        buffer_pool_manager.log_manager = Some(log_manager.clone());
+        let buffer_pool_manager = Arc::new(RwLock::new(buffer_pool_manager));
+        let mut recovery_manager =
+            RecoveryManager::new(log_manager.clone(), buffer_pool_manager.clone());
+        let max_transaction_id = recovery_manager.run();
+        log_manager.write().unwrap().max_transaction_id = max_transaction_id;
+        // A checkpoint takes 2 lsns, so only spend them if recovery has work to
+        // save. Without one, the next load just repeats the recovery.
+        if recovery_manager.has_changes {
+            let _ =
+                Checkpointer::new(log_manager.clone(), buffer_pool_manager.clone()).checkpoint();
+        }
+        let last_page_id = buffer_pool_manager
+            .read()
+            .unwrap()
+            .page_manager
+            .next_page_id()
+            - 1;
+        Self {
+            log_manager,
+            buffer_pool_manager,
+            lock_manager: Arc::new(LockManager::new()),
+            commit_status: Arc::new(CommitStatusTable::default()),
+            current_transaction_id: AtomicU8::new(max_transaction_id + 1),
+            last_page_id: Arc::new(AtomicU8::new(last_page_id)),
+            checkpointer: Mutex::new(None),
+            wal_sender: Mutex::new(None),
+            wal_receiver: Mutex::new(None),
+            hot_standby: None,
+            corrupted_pages: Mutex::new(recovery_manager.corrupted_pages),
+            default_isolation_level: Mutex::new(IsolationLevel::Snapshot),
+        }
+    }
+    // Opens a copy of the primary, e.g. a base backup, as a standby that
+    // replays the primary's log until it is promoted.
+    fn standby(
+        file_name: &str,
+        log_file_name: &str,
+        options: DatabaseOptions,
+        primary_address: &str,
+    ) -> io::Result<Self> {
+        let log_manager = Arc::new(RwLock::new(LogManager::load(log_file_name)));
+        let page_manager = PageManager::load(file_name, options.direct_io);
+        let mut buffer_pool_manager =
+            BufferPoolManager::new(page_manager, options.buffer_pool_max_frame_length);
+        buffer_pool_manager.log_manager = Some(log_manager.clone());
+        let buffer_pool_manager = Arc::new(RwLock::new(buffer_pool_manager));
+        let mut recovery_manager =
+            RecoveryManager::new(log_manager.clone(), buffer_pool_manager.clone());
+        let logs = recovery_manager.replay();
+        let mut in_flight_changes = InFlightChanges::default();
+        if let Some(first_lsn) = recovery_manager
+            .transaction_table
+            .values()
+            .map(|entry| entry.first_lsn)
+            .min()
+        {
+            for log in logs.iter().filter(|log| log.lsn >= first_lsn) {
+                in_flight_changes.apply(log);
+            }
+        }
+        let hot_standby = Arc::new(HotStandby {
+            state: Mutex::new(HotStandbyState {
+                readers: Vec::new(),
+                max_standby_delay: DEFAULT_MAX_STANDBY_DELAY,
+                replayed_lsn: logs.last().map(|log| log.lsn),
+                in_flight_changes,
+                replication_error: None,
+            }),
+            condvar: Condvar::new(),
+        });
+        // The log is on disk, so the redone pages can be written and counted.
+        let last_page_id = {
+            let mut buffer_pool_manager = buffer_pool_manager.write().unwrap();
+            buffer_pool_manager.flush_dirty_pages();
+            buffer_pool_manager.page_manager.next_page_id() - 1
+        };
+        let last_page_id = Arc::new(AtomicU8::new(last_page_id));
+        let mut stream = TcpStream::connect(primary_address)?;
+        let next_lsn = log_manager
+            .read()
+            .unwrap()
+            .current_lsn
+            .expect("log sequence numbers are exhausted");
+        stream.write_all(&[next_lsn])?;
+        let wal_receiver = WalReceiver {
+            log_manager: log_manager.clone(),
+            buffer_pool_manager: buffer_pool_manager.clone(),
+            hot_standby: hot_standby.clone(),
+            last_page_id: last_page_id.clone(),
+        };
+        let handle = wal_receiver.spawn(stream.try_clone()?);
+        Ok(Self {
+            log_manager,
+            buffer_pool_manager,
+            lock_manager: Arc::new(LockManager::new()),
+            commit_status: Arc::new(CommitStatusTable::default()),
+            current_transaction_id: AtomicU8::new(0),
+            last_page_id,
+            checkpointer: Mutex::new(None),
+            wal_sender: Mutex::new(None),
+            wal_receiver: Mutex::new(Some((stream, handle))),
+            hot_standby: Some(hot_standby),
+            corrupted_pages: Mutex::new(recovery_manager.corrupted_pages),
+            default_isolation_level: Mutex::new(IsolationLevel::Snapshot),
+        })
+    }
+    // Stops replaying and turns a standby into a primary. Transactions the old
+    // primary had not finished are rolled back, as after a crash. Read-only
+    // transactions still open then are canceled.
+    fn promote(&self) {
+        let (stream, handle) = self
+            .wal_receiver
+            .lock()
+            .unwrap()
+            .take()
+            .expect("database is not a standby");
+        // The primary may already have closed the connection.
+        let _ = stream.shutdown(Shutdown::Both);
+        handle.join().unwrap();
+        // Rolling back below changes pages they may read.
+        self.hot_standby
+            .as_ref()
+            .unwrap()
+            .state
+            .lock()
+            .unwrap()
+            .cancel_readers();
+        let mut recovery_manager =
+            RecoveryManager::new(self.log_manager.clone(), self.buffer_pool_manager.clone());
+        let max_transaction_id = recovery_manager.run();
+        self.log_manager.write().unwrap().max_transaction_id = max_transaction_id;
+        self.corrupted_pages
+            .lock()
+            .unwrap()
+            .extend(recovery_manager.corrupted_pages);
+        self.current_transaction_id
+            .store(max_transaction_id + 1, Ordering::Relaxed);
+        if recovery_manager.has_changes {
+            let _ = self.checkpoint();
+        }
+        let last_page_id = self
+            .buffer_pool_manager
+            .read()
+            .unwrap()
+            .page_manager
+            .next_page_id()
+            - 1;
+        self.last_page_id.store(last_page_id, Ordering::Relaxed);
+    }
+    // Why a standby stopped replicating, None while it still follows the
+    // primary.
+    fn replication_error(&self) -> Option<ReplicationError> {
+        self.hot_standby
+            .as_ref()?
+            .state
+            .lock()
+            .unwrap()
+            .replication_error
+    }
+    // Pages that recovery found corrupted, e.g. torn by a crash, and rebuilt
+    // from the log.
+    fn corrupted_pages(&self) -> Vec<PageCorrupted> {
+        self.corrupted_pages.lock().unwrap().clone()
+    }
+    fn is_standby(&self) -> bool {
+        self.wal_receiver.lock().unwrap().is_some()
+    }
+    // On a standby, the lsn up to which the pages have been replayed.
+    fn replayed_lsn(&self) -> Option<u8> {
+        self.hot_standby
+            .as_ref()?
+            .state
+            .lock()
+            .unwrap()
+            .replayed_lsn
+    }
+    // How long replay waits for the read-only transactions of a standby
+    // before it cancels them.
+    fn set_max_standby_delay(&self, max_standby_delay: Duration) {
+        self.hot_standby
+            .as_ref()
+            .expect("database is not a standby")
+            .state
+            .lock()
+            .unwrap()
+            .max_standby_delay = max_standby_delay;
+    }
+    // Accepts standby connections on address, e.g. "127.0.0.1:0", and returns
+    // the address actually bound.
+    fn start_wal_sender(&self, address: &str) -> SocketAddr {
+        let listener = TcpListener::bind(address).unwrap();
+        let wal_sender = WalSender::new(&self.log_manager).spawn(listener);
+        let local_address = wal_sender.address;
+        let previous = self.wal_sender.lock().unwrap().replace(wal_sender);
+        assert!(previous.is_none(), "wal sender is already running");
+        local_address
+    }
+    // Rebuilds the database from a base backup and archived log segments,
+    // replaying no further than target. Transactions without a commit by then
+    // are rolled back.
+    fn restore(
+        file_name: &str,
+        log_file_name: &str,
+        options: DatabaseOptions,
+        restore_options: RestoreOptions,
+    ) -> Result<Self, RestoreError> {
+        let RestoreOptions {
+            base_backup_dir_name,
+            archive_dir_name,
+            target,
+        } = restore_options;
+        let label = BackupLabel::read(base_backup_dir_name);
+        let mut logs = Vec::new();
+        let mut replayed_lsn = None;
+        let mut reached_target = false;
+        for log in LogReader::open(archive_dir_name).skip_while(|log| log.lsn < label.start_lsn) {
+            // A missing segment ends the archive, later records cannot be replayed.
+            if Some(log.lsn)
+                != replayed_lsn.map_or(Some(label.start_lsn), |lsn: u8| lsn.checked_add(1))
+            {
+                break;
+            }
+            if target.stops_before(&log) {
+                reached_target = true;
+                break;
+            }
+            reached_target = target.stops_after(&log);
+            replayed_lsn = Some(log.lsn);
+            match log.log_type {
+                // Checkpoints taken after the backup describe pages it does not have.
+                LogType::EndCheckpoint(_) if log.lsn > label.end_lsn => {}
+                _ => logs.push(log),
+            }
+            if reached_target {
+                break;
+            }
+        }
+        match replayed_lsn {
+            Some(lsn) if lsn >= label.end_lsn => {}
+            _ if reached_target => {
+                return Err(RestoreError::TargetBeforeBackupEnd {
+                    end_lsn: label.end_lsn,
+                })
+            }
+            _ => {
+                return Err(RestoreError::MissingArchive {
+                    lsn: replayed_lsn.map_or(label.start_lsn, |lsn| lsn + 1),
+                })
+            }
+        }
+        fs::copy(
+            Path::new(base_backup_dir_name).join(BackupLabel::DATA_FILE_NAME),
+            file_name,
+        )
+        .unwrap();
+        LogManager::create(log_file_name, &logs);
+        Ok(Database::load_with_options(
+            file_name,
+            log_file_name,
+            options,
+        ))
+    }
+    // On a standby, returns a read-only transaction that sees the primary's
+    // committed changes as of the lsn replayed so far.
+    fn begin(&self) -> Transaction {
+        if self.is_standby() {
+            let mut transaction = Transaction::new(
+                0,
+                self.lock_manager.clone(),
+                self.commit_status.clone(),
+                self.log_manager.clone(),
+            );
+            transaction.snapshot = Some(HotStandby::snapshot(self.hot_standby.as_ref().unwrap()));
+            return transaction;
+        }
+        // Ids do not wrap around, as tuples keep the ids of the transactions
+        // that wrote them. The last one, INVALID_TRANSACTION_ID, is never
+        // handed out.
+        let transaction_id = self
+            .current_transaction_id
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |transaction_id| {
+                transaction_id.checked_add(1)
+            })
+            .expect("transaction ids are exhausted");
+        let mut transaction = Transaction::new(
+            transaction_id,
+            self.lock_manager.clone(),
+            self.commit_status.clone(),
+            self.log_manager.clone(),
+        );
+        transaction.mvcc_snapshot = Some(CommitStatusTable::begin(
+            &self.commit_status,
+            transaction_id,
+        ));
+        transaction.isolation_level = *self.default_isolation_level.lock().unwrap();
+        transaction.log_begin();
+        transaction
+    }
+    fn resize_buffer_pool(&self, max_frame_length: usize) -> usize {
+        self.buffer_pool_manager
+            .write()
+            .unwrap()
+            .resize(max_frame_length)
+    }
+    fn checkpoint(&self) -> Result<u8, CheckpointError> {
+        Checkpointer::new(self.log_manager.clone(), self.buffer_pool_manager.clone()).checkpoint()
+    }
+    fn enable_wal_archive(&self, archive_dir_name: &str) {
+        self.log_manager
+            .read()
+            .unwrap()
+            .set_archive_dir(archive_dir_name);
+    }
+    fn switch_log_segment(&self) {
+        LogManager::switch_segment(&self.log_manager);
+    }
+    // Copies a running database into backup_dir_name. The copy holds the data
+    // file, the log from start_lsn to end_lsn and a backup label, so
+    // Database::load can open it directly.
+    fn base_backup(&self, backup_dir_name: &str) -> Result<(), CheckpointError> {
+        self.backup(backup_dir_name, None)
+    }
+    // Like base_backup, but only copies the pages changed since the backup in
+    // parent_dir_name started. merge_backups turns a chain of them back into a
+    // full backup.
+    fn incremental_backup(
+        &self,
+        backup_dir_name: &str,
+        parent_dir_name: &str,
+    ) -> Result<(), CheckpointError> {
+        self.backup(
+            backup_dir_name,
+            Some(BackupLabel::read(parent_dir_name).start_lsn),
+        )
+    }
+    fn backup(
+        &self,
+        backup_dir_name: &str,
+        incremental_from: Option<u8>,
+    ) -> Result<(), CheckpointError> {
+        let start_lsn = self.checkpoint()?;
+        self.log_manager
+            .write()
+            .unwrap()
+            .backup_start_lsns
+            .push(start_lsn);
+        // A fuzzy copy: transactions keep running while the pages are copied,
+        // and the log from start_lsn to end_lsn brings every page up to date.
+        // Pages only reach the disk through the buffer pool, so holding it for
+        // one page keeps the copy from seeing a half written page.
+        let page_count = self
+            .buffer_pool_manager
+            .read()
+            .unwrap()
+            .page_manager
+            .next_page_id();
+        let pages: Vec<Page> = (0..page_count)
+            .map(|page_id| {
+                self.buffer_pool_manager
+                    .write()
+                    .unwrap()
+                    .page_manager
+                    .read_page(page_id)
+                    .unwrap_or_else(|error| panic!("{:?}", error))
+            })
+            .collect();
+        // Every page copied above has a page_lsn up to here.
+        let end_lsn = self.log_manager.read().unwrap().last_lsn().unwrap();
+        LogManager::flush_all(&self.log_manager);
+        let log_dir_name = self.log_manager.read().unwrap().dir_name.clone();
+        let label = BackupLabel {
+            start_lsn,
+            end_lsn,
+            incremental_from,
+        };
+        // backup_start_lsns keeps the log from start_lsn on.
+        assert!(Database::write_backup(
+            backup_dir_name,
+            &pages,
+            &log_dir_name,
+            &label
+        ));
+        let mut log_manager = self.log_manager.write().unwrap();
+        let index = log_manager
+            .backup_start_lsns
+            .iter()
+            .position(|lsn| *lsn == start_lsn)
+            .unwrap();
+        log_manager.backup_start_lsns.swap_remove(index);
+        Ok(())
+    }
+    // Same as base_backup and incremental_backup, but only through the files of
+    // a database that may be running in another process.
+    fn backup_files(
+        file_name: &str,
+        log_file_name: &str,
+        backup_dir_name: &str,
+        parent_dir_name: Option<&str>,
+    ) {
+        // The other process may truncate the log between reading its last
+        // checkpoint and writing the start file, so a copy that misses
+        // start_lsn starts over from the then last checkpoint.
+        loop {
+            let start_lsn = LogReader::open(log_file_name)
+                .filter_map(|log| match log.log_type {
+                    LogType::EndCheckpoint(end_checkpoint_log) => Some(
+                        end_checkpoint_log
+                            .dirty_page_table
+                            .iter()
+                            .map(|(_, rec_lsn)| *rec_lsn)
+                            .chain(
+                                end_checkpoint_log
+                                    .transaction_table
+                                    .iter()
+                                    .map(|(_, entry)| entry.first_lsn),
+                            )
+                            .fold(end_checkpoint_log.begin_checkpoint_lsn, u8::min),
+                    ),
+                    _ => None,
+                })
+                .last()
+                .unwrap_or(0);
+            BackupLabel::write_start(log_file_name, backup_dir_name, start_lsn);
+            let mut page_manager = PageManager::load(file_name, false);
+            let pages: Vec<Page> = (0..page_manager.next_page_id())
+                .map(|page_id| page_manager.read_page_while_written(page_id))
+                .collect();
+            // Every page above was written after its log records became durable.
+            let end_lsn = LogReader::open(log_file_name).last().unwrap().lsn;
+            let label = BackupLabel {
+                start_lsn,
+                end_lsn,
+                incremental_from: parent_dir_name
+                    .map(|parent_dir_name| BackupLabel::read(parent_dir_name).start_lsn),
+            };
+            if Database::write_backup(backup_dir_name, &pages, log_file_name, &label) {
+                break;
+            }
+        }
+        fs::remove_file(BackupLabel::start_path(log_file_name, backup_dir_name)).unwrap();
+    }
+    // Returns false, without writing anything, if the log no longer has
+    // start_lsn.
+    fn write_backup(
+        backup_dir_name: &str,
+        pages: &[Page],
+        log_dir_name: &str,
+        label: &BackupLabel,
+    ) -> bool {
+        let BackupLabel {
+            start_lsn, end_lsn, ..
+        } = *label;
+        let logs: Vec<Log> = LogReader::open(log_dir_name)
+            .skip_while(|log| log.lsn < start_lsn)
+            .take_while(|log| log.lsn <= end_lsn)
+            .collect();
+        if logs.first().map(|log| log.lsn) != Some(start_lsn) {
+            return false;
+        }
+        let backup_dir = Path::new(backup_dir_name);
+        fs::create_dir_all(backup_dir).unwrap();
+        let mut data_file = File::create(backup_dir.join(BackupLabel::DATA_FILE_NAME)).unwrap();
+        // Changes from incremental_from on are either in a page newer than it
+        // or in the log copied below, so older pages can be left out.
+        for page in pages.iter().filter(|page| {
+            label
+                .incremental_from
+                .is_none_or(|lsn| page.page_lsn() >= lsn)
+        }) {
+            data_file.write_all(&page.bytes).unwrap();
+        }
+        data_file.sync_all().unwrap();
+        LogManager::create(
+            backup_dir.join(BackupLabel::LOG_DIR_NAME).to_str().unwrap(),
+            &logs,
+        );
+        label.write(backup_dir_name);
+        true
+    }
+    // Applies a chain of incremental backups to a full backup and writes the
+    // result to output_dir_name as a full backup.
+    fn merge_backups(backup_dir_names: &[&str], output_dir_name: &str) {
+        let (full_dir_name, incremental_dir_names) = backup_dir_names.split_first().unwrap();
+        let mut label = BackupLabel::read(full_dir_name);
+        assert!(
+            label.incremental_from.is_none(),
+            "{} is not a full backup",
+            full_dir_name
+        );
+        let output_dir = Path::new(output_dir_name);
+        fs::create_dir_all(output_dir).unwrap();
+        let data_file_name = output_dir.join(BackupLabel::DATA_FILE_NAME);
+        fs::copy(
+            Path::new(full_dir_name).join(BackupLabel::DATA_FILE_NAME),
+            &data_file_name,
+        )
+        .unwrap();
+        let mut page_manager = PageManager::load(data_file_name.to_str().unwrap(), false);
+        let mut log_dir_name = Path::new(full_dir_name).join(BackupLabel::LOG_DIR_NAME);
+        for incremental_dir_name in incremental_dir_names {
+            let incremental_label = BackupLabel::read(incremental_dir_name);
+            assert_eq!(
+                incremental_label.incremental_from,
+                Some(label.start_lsn),
+                "{} does not follow the previous backup",
+                incremental_dir_name
+            );
+            let bytes = fs::read(Path::new(incremental_dir_name).join(BackupLabel::DATA_FILE_NAME))
+                .unwrap();
+            for chunk in bytes.chunks_exact(PAGE_SIZE) {
+                page_manager.write_page_unsynced(&Page::load(chunk.try_into().unwrap()));
+            }
+            label = incremental_label;
+            log_dir_name = Path::new(incremental_dir_name).join(BackupLabel::LOG_DIR_NAME);
+        }
+        page_manager.sync();
+        // Only the last backup's log is needed to make the merged pages consistent.
+        let logs: Vec<Log> = LogReader::open(log_dir_name.to_str().unwrap()).collect();
+        LogManager::create(
+            output_dir.join(BackupLabel::LOG_DIR_NAME).to_str().unwrap(),
+            &logs,
+        );
+        BackupLabel {
+            incremental_from: None,
+            ..label
+        }
+        .write(output_dir_name);
+    }
+    fn start_checkpointer(&self, interval: Duration) {
+        let checkpointer =
+            Checkpointer::new(self.log_manager.clone(), self.buffer_pool_manager.clone());
+        let previous = self
+            .checkpointer
+            .lock()
+            .unwrap()
+            .replace(checkpointer.spawn(interval));
+        assert!(previous.is_none(), "checkpointer is already running");
+    }
+    fn stop_checkpointer(&self) -> Result<(), CheckpointError> {
+        self.checkpointer
+            .lock()
+            .unwrap()
+            .take()
+            .expect("checkpointer is not running")
+            .stop()
+    }
+    // Makes every commit wait, for at most timeout, until that many standbys
+    // have flushed it. 0 standbys means async replication.
+    fn set_synchronous_standbys(&self, standbys: usize, timeout: Duration) {
+        self.log_manager
+            .read()
+            .unwrap()
+            .sync_replication
+            .configure(standbys, timeout);
+    }
+    // Isolation level of the transactions begun from now on. Snapshot by
+    // default; RepeatableRead and Serializable read with locks instead.
+    fn set_default_isolation_level(&self, isolation_level: IsolationLevel) {
+        *self.default_isolation_level.lock().unwrap() = isolation_level;
+    }
+    // Transactions holding more row locks than the threshold get a table lock
+    // instead.
+    fn set_lock_escalation_threshold(&self, threshold: usize) {
+        self.lock_manager
+            .escalation_threshold
+            .store(threshold, Ordering::Relaxed);
+    }
+    // Transactions taking a row lock while the lock table uses more bytes than
+    // the budget get a table lock instead, like past the escalation threshold.
+    fn set_lock_memory_budget(&self, bytes: usize) {
+        self.lock_manager
+            .memory_budget
+            .store(bytes, Ordering::Relaxed);
+    }
+    // Committed transactions from the position saved in slot_name on, or those
+    // committing from now on if the slot is new. The stream follows new commits; consumers save
+    // the position of what they have processed with confirm_logical_position.
+    fn logical_changes(&self, slot_name: &str) -> Result<LogicalDecoder, LogicalDecodingError> {
+        let log_manager = self.log_manager.read().unwrap();
+        let position =
+            LogicalPosition::read(&log_manager.dir_name, slot_name).unwrap_or_else(|| {
+                // A new slot starts at the Begin of the oldest running
+                // transaction, so its changes so far are decoded too. Commits
+                // before now are already confirmed.
+                let position = LogicalPosition {
+                    restart_lsn: log_manager
+                        .transaction_table
+                        .values()
+                        .map(|entry| entry.first_lsn)
+                        .min()
+                        .or(log_manager.current_lsn)
+                        .expect("log sequence numbers are exhausted"),
+                    confirmed_lsn: log_manager.last_lsn(),
+                };
+                position.write(&log_manager.dir_name, slot_name);
+                position
+            });
+        let logs = LogStream::subscribe(
+            &log_manager.group_commit,
+            &log_manager.dir_name,
+            position.restart_lsn,
+        );
+        LogicalDecoder::new(&log_manager.dir_name, logs, position)
+    }
+    fn confirm_logical_position(&self, slot_name: &str, position: LogicalPosition) {
+        position.write(&self.log_manager.read().unwrap().dir_name, slot_name);
+    }
+    fn set_commit_delay(&self, commit_delay: Duration) {
+        self.log_manager
+            .read()
+            .unwrap()
+            .set_commit_delay(commit_delay);
+    }
+    // Segments take effect from the next one started.
+    fn set_log_segment_size(&self, segment_size: usize) {
+        self.log_manager
+            .read()
+            .unwrap()
+            .set_segment_size(segment_size);
+    }
+    fn read_system_view(&self, view: SystemView) -> Vec<Vec<String>> {
+        let buffer_pool_manager = self.buffer_pool_manager.read().unwrap();
+        match view {
+            SystemView::BufferPoolStatistics => {
+                let statistics = buffer_pool_manager.statistics();
+                vec![vec![
+                    statistics.hits.to_string(),
+                    statistics.misses.to_string(),
+                    statistics.evictions.to_string(),
+                    statistics.dirty_writes.to_string(),
+                    statistics.shared_pin_hits.to_string(),
+                ]]
+            }
+            SystemView::BufferPoolFrames => buffer_pool_manager
+                .snapshot()
+                .into_iter()
+                .map(|frame| {
+                    vec![
+                        frame.frame_id.to_string(),
+                        frame.page_id.to_string(),
+                        frame.pin_count.to_string(),
+                        frame.is_dirty.to_string(),
+                        frame.last_access.to_string(),
+                    ]
+                })
+                .collect(),
+            SystemView::Locks => self
+                .lock_manager
+                .snapshot()
+                .into_iter()
+                .map(|lock| {
+                    vec![
+                        lock.resource.name(),
+                        lock.lock_type.name().to_string(),
+                        lock.transaction_id.to_string(),
+                        lock.wait_time.is_none().to_string(),
+                        lock.wait_time
+                            .map_or(0, |wait_time| wait_time.as_millis())
+                            .to_string(),
+                    ]
+                })
+                .collect(),
+            SystemView::LockWaits => self
+                .lock_manager
+                .wait_for_graph
+                .edges()
+                .into_iter()
+                .map(|(waiter, holder)| vec![waiter.to_string(), holder.to_string()])
+                .collect(),
+        }
+    }
+    fn commit(&self, transaction: &mut Transaction) {
+        transaction.commit();
+    }
+    fn abort(&self, transaction: &mut Transaction) {
+        let logs = transaction.logs.clone();
+        for log in logs.iter().rev() {
+            match &log.log_type {
+                LogType::Insert(ref insert_log) => {
+                    let page = self
+                        .buffer_pool_manager
+                        .write()
+                        .unwrap()
+                        .read_page(insert_log.page_id);
+                    {
+                        let mut page = page.write().unwrap();
+                        page.rollback_insert(
+                            insert_log.slot_id,
+                            Some((transaction, insert_log.prev_lsn)),
+                        );
+                    }
+                    self.buffer_pool_manager
+                        .write()
+                        .unwrap()
+                        .unpin_page(insert_log.page_id, true);
+                }
+                LogType::CompensateInsert(_) => {}
+                LogType::Delete(ref delete_log) => {
+                    let page = self
+                        .buffer_pool_manager
+                        .write()
+                        .unwrap()
+                        .read_page(delete_log.page_id);
+                    {
+                        let mut page = page.write().unwrap();
+                        page.rollback_delete(
+                            delete_log.slot_id,
+                            Some((transaction, delete_log.prev_lsn)),
+                        );
+                    }
+                    self.buffer_pool_manager
+                        .write()
+                        .unwrap()
+                        .unpin_page(delete_log.page_id, true);
+                }
+                LogType::CompensateDelete(_) => {}
+                LogType::Begin(_) => {}
+                LogType::Commit(_) => {}
+                LogType::Abort(_) => {}
+                LogType::BeginCheckpoint => {}
+                LogType::EndCheckpoint(_) => {}
+            }
+        }
+        transaction.abort();
+    }
+    // Panics if a lock cannot be taken, e.g. on a deadlock. try_insert returns
+    // the error instead.
+    fn insert(&self, transaction: &mut Transaction, tuple: u8) {
+        self.try_insert(transaction, tuple).unwrap();
+    }
+    // Like insert, but aborts the transaction and returns the error when a
+    // lock cannot be taken.
+    fn try_insert(&self, transaction: &mut Transaction, tuple: u8) -> Result<(), LockError> {
+        if transaction.snapshot.is_some() {
+            self.abort(transaction);
+            return Err(LockError::ReadOnlyTransaction);
+        }
+        let result = loop {
+            let page_id = self.last_page_id.load(Ordering::Relaxed);
+            // Locked before latching the page, like in read_page_tuples. The
+            // row lock taken under the latch is on a new slot, which nobody
+            // else can hold.
+            if let Err(error) =
+                transaction.lock(LockResource::Page(page_id), LockType::IntentionExclusive)
+            {
+                self.abort(transaction);
+                return Err(error);
+            }
+            let page = self.buffer_pool_manager.write().unwrap().read_page(page_id);
+            let result = {
+                let mut page = page.write().unwrap();
+                let xmin = transaction.transaction_id;
+                page.has_space()
+                    .then(|| page.insert_tuple(tuple, xmin, Some(transaction)))
+            };
+            self.buffer_pool_manager
+                .write()
+                .unwrap()
+                .unpin_page(page_id, result.is_some());
+            if let Some(result) = result {
+                break result;
+            }
+            // The page is full, so the next page is allocated, unless another
+            // transaction already has, and locked on the next try.
+            let mut buffer_pool_manager = self.buffer_pool_manager.write().unwrap();
+            if self.last_page_id.load(Ordering::Relaxed) == page_id {
+                let new_page_id = buffer_pool_manager
+                    .allocate_page()
+                    .read()
+                    .unwrap()
+                    .page_id();
+                buffer_pool_manager.unpin_page(new_page_id, false);
+                self.last_page_id.store(new_page_id, Ordering::Relaxed);
+            }
+        };
+        if result.is_err() {
+            self.abort(transaction);
+        }
+        result
+    }
+    // Panics if a lock cannot be taken, e.g. on a deadlock. try_delete returns
+    // the error instead.
+    fn delete(&self, transaction: &mut Transaction, tuple: u8) -> usize {
+        self.try_delete(transaction, tuple).unwrap()
+    }
+    // Deletes the tuples equal to tuple and returns how many. A snapshot
+    // transaction only deletes the rows its snapshot sees. Aborts the
+    // transaction and returns the error when a lock cannot be taken.
+    fn try_delete(&self, transaction: &mut Transaction, tuple: u8) -> Result<usize, LockError> {
+        if transaction.snapshot.is_some() {
+            self.abort(transaction);
+            return Err(LockError::ReadOnlyTransaction);
+        }
+        let mut deleted = 0;
+        let mut page_id = 0;
+        loop {
+            let page = self.buffer_pool_manager.write().unwrap().read_page(page_id);
+            let result = Database::delete_page_tuples(&page, transaction, tuple);
+            self.buffer_pool_manager
+                .write()
+                .unwrap()
+                .unpin_page(page_id, result.as_ref().is_ok_and(|count| *count > 0));
+            match result {
+                Ok(count) => deleted += count,
+                Err(error) => {
+                    self.abort(transaction);
+                    return Err(error);
+                }
+            }
+            if self.last_page_id.load(Ordering::Relaxed) > page_id {
+                page_id += 1;
+            } else {
+                break;
+            }
+        }
+        Ok(deleted)
+    }
+    // Tuples never change once inserted, so the slots to delete are found
+    // before locking them, and checked again under the latch once locked.
+    // Slots another transaction deleted are locked too, as the delete may
+    // still be rolled back.
+    fn delete_page_tuples(
+        page: &RwLock<Page>,
+        transaction: &mut Transaction,
+        tuple: u8,
+    ) -> Result<usize, LockError> {
+        let mvcc_snapshot = match (transaction.isolation_level, &transaction.mvcc_snapshot) {
+            (IsolationLevel::Snapshot, Some(mvcc_snapshot)) => Some(mvcc_snapshot),
+            _ => None,
+        };
+        let (page_id, slot_ids) = {
+            let page = page.read().unwrap();
+            let slot_ids: Vec<u8> = (0..page.tuple_length())
+                .filter(|slot_id| !page.is_dead(*slot_id) && page.read_tuple(*slot_id) == tuple)
+                .filter(|slot_id| {
+                    mvcc_snapshot.is_none_or(|mvcc_snapshot| {
+                        mvcc_snapshot.sees(page.xmin(*slot_id), page.xmax(*slot_id))
+                    })
+                })
+                .collect();
+            (page.page_id(), slot_ids)
+        };
+        let is_snapshot = mvcc_snapshot.is_some();
+        for slot_id in &slot_ids {
+            transaction.lock(
+                LockResource::Row(RowID(page_id, *slot_id)),
+                LockType::Exclusive,
+            )?;
+        }
+        let mut page = page.write().unwrap();
+        // First deleter wins: a row the snapshot sees that was deleted since
+        // is a lost update for a snapshot transaction, so it fails instead.
+        if is_snapshot
+            && slot_ids.iter().any(|slot_id| {
+                !page.is_dead(*slot_id) && page.xmax(*slot_id) != INVALID_TRANSACTION_ID
+            })
+        {
+            return Err(LockError::SerializationFailure);
+        }
+        let mut deleted = 0;
+        for slot_id in slot_ids {
+            // Rolled back, or deleted by a transaction that held the lock.
+            if !page.is_dead(slot_id) && page.xmax(slot_id) == INVALID_TRANSACTION_ID {
+                let xmax = transaction.transaction_id;
+                page.delete_tuple(slot_id, xmax, Some(transaction));
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+    // Panics if a lock cannot be taken, e.g. on a deadlock. try_read_all
+    // returns the error instead.
+    fn read_all(&self, transaction: &mut Transaction) -> Vec<u8> {
+        self.try_read_all(transaction).unwrap()
+    }
+    // Like read_all, but aborts the transaction and returns the error when a
+    // lock cannot be taken.
+    fn try_read_all(&self, transaction: &mut Transaction) -> Result<Vec<u8>, LockError> {
+        if transaction.isolation_level == IsolationLevel::Serializable
+            && transaction.lock_wait != LockWait::SkipLocked
+        {
+            if let Err(error) = transaction.lock(LockResource::Table, LockType::Shared) {
+                self.abort(transaction);
+                return Err(error);
+            }
+        }
+        let mut values = Vec::new();
+        let mut page_id = 0;
+        loop {
+            let page = self.buffer_pool_manager.write().unwrap().read_page(page_id);
+            let result = Database::read_page_tuples(&page, transaction);
+            self.buffer_pool_manager
+                .write()
+                .unwrap()
+                .unpin_page(page_id, false);
+            match result {
+                Ok(tuples) => values.extend(tuples),
+                Err(error) => {
+                    self.abort(transaction);
+                    return Err(error);
+                }
+            }
+            if self.last_page_id.load(Ordering::Relaxed) > page_id {
+                page_id += 1;
+            } else {
+                break;
+            }
+        }
+        Ok(values)
+    }
+    // Aborts the transaction and returns the error when the lock cannot be
+    // taken, like try_insert.
+    fn lock_table(
+        &self,
+        transaction: &mut Transaction,
+        lock_type: LockType,
+    ) -> Result<(), LockError> {
+        let result = transaction.lock(LockResource::Table, lock_type);
+        if result.is_err() {
+            self.abort(transaction);
+        }
+        result
+    }
+    fn read_page_tuples(
+        page: &RwLock<Page>,
+        transaction: &mut Transaction,
+    ) -> Result<Vec<u8>, LockError> {
+        if let (IsolationLevel::Snapshot, Some(mvcc_snapshot)) =
+            (transaction.isolation_level, &transaction.mvcc_snapshot)
+        {
+            // Only latched, which the writers of the page hold briefly.
+            let page = page.read().unwrap();
+            let slot_ids: Vec<u8> = (0..page.tuple_length())
+                .filter(|slot_id| mvcc_snapshot.sees(page.xmin(*slot_id), page.xmax(*slot_id)))
+                .collect();
+            return Ok(page.read_tuples(&slot_ids));
+        }
+        // Waits for locks before latching the page, so the rollback of a
+        // transaction holding one of them is never blocked by the waiter.
+        let page_id = page.read().unwrap().page_id();
+        // A single page lock unless locked rows have to be skipped.
+        if transaction.lock_wait != LockWait::SkipLocked {
+            transaction.lock(LockResource::Page(page_id), LockType::Shared)?;
+        }
+        let tuple_length = page.read().unwrap().tuple_length();
+        let mut slot_ids = Vec::new();
+        for slot_id in 0..tuple_length {
+            if !transaction.is_visible(page_id, slot_id) {
+                continue;
+            }
+            match transaction.pre_read(page_id, slot_id) {
+                Ok(()) => slot_ids.push(slot_id),
+                Err(LockError::LockTimeout) if transaction.lock_wait == LockWait::SkipLocked => {}
+                Err(error) => return Err(error),
+            }
+        }
+        // Slots added after tuple_length was read are not in slot_ids, so no
+        // lock is taken while the page is latched. A delete holds the row lock
+        // until it commits, so a set xmax is committed, or this transaction's.
+        let page = page.read().unwrap();
+        slot_ids.retain(|slot_id| !transaction.is_deleted(&page, *slot_id));
+        let tuples = page.read_tuples(&slot_ids);
+        transaction.check_replay_conflict()?;
+        Ok(tuples)
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        if let Some(checkpointer) = self.checkpointer.lock().unwrap().take() {
+            let _ = checkpointer.stop();
+        }
+        if let Some(wal_sender) = self.wal_sender.lock().unwrap().take() {
+            wal_sender.stop();
+        }
+        if let Some((stream, handle)) = self.wal_receiver.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Debug for Database {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:?}", self.buffer_pool_manager)?;
+        Ok(())
+    }
+}
+// Prints the records of a log directory or a single segment file, one line
+// per record, as text or as JSON objects.
+#[derive(Default)]
+struct WalDump {
+    start_lsn: Option<u8>,
+    end_lsn: Option<u8>,
+    transaction_id: Option<u8>,
+    page_id: Option<u8>,
+    json: bool,
+}
+
+impl WalDump {
+    const USAGE: &'static str =
+        "07 waldump <log dir | segment file> [--start <lsn>] [--end <lsn>] \
+[--transaction <id>] [--page <id>] [--json]";
+
+    fn parse(options: &[&str]) -> Option<Self> {
+        let mut wal_dump = Self::default();
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            if *option == "--json" {
+                wal_dump.json = true;
+                continue;
+            }
+            let value = Some(options.next()?.parse().ok()?);
+            match *option {
+                "--start" => wal_dump.start_lsn = value,
+                "--end" => wal_dump.end_lsn = value,
+                "--transaction" => wal_dump.transaction_id = value,
+                "--page" => wal_dump.page_id = value,
+                _ => return None,
+            }
+        }
+        Some(wal_dump)
+    }
+    fn matches(&self, log: &Log) -> bool {
+        self.start_lsn.is_none_or(|lsn| log.lsn >= lsn)
+            && self.end_lsn.is_none_or(|lsn| log.lsn <= lsn)
+            && self
+                .transaction_id
+                .is_none_or(|id| log.log_type.transaction_id() == Some(id))
+            && self
+                .page_id
+                .is_none_or(|id| log.log_type.page_id() == Some(id))
+    }
+    fn run(&self, path: &str) {
+        let mut reader = if Path::new(path).is_dir() {
+            LogReader::open(path)
+        } else {
+            LogReader::open_segment(path)
+        };
+        for log in reader.by_ref().filter(|log| self.matches(log)) {
+            let fields = WalDump::fields(&log);
+            if self.json {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(key, value)| {
+                        format!("\"{}\":{}", key, value.as_deref().unwrap_or("null"))
+                    })
+                    .collect();
+                println!(
+                    "{{\"lsn\":{},\"type\":\"{}\",{}}}",
+                    log.lsn,
+                    log.log_type.name(),
+                    fields.join(",")
+                );
+            } else {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value.as_deref().unwrap_or("-")))
+                    .collect();
+                println!(
+                    "{:>3} {:<16} {}",
+                    log.lsn,
+                    log.log_type.name(),
+                    fields.join(" ")
+                );
+            }
+        }
+        if let Some((segment_lsn, offset)) = reader.torn_at {
+            eprintln!(
+                "log is torn in segment {:03} at offset {}",
+                segment_lsn, offset
+            );
+        }
+    }
+    // Values are already JSON literals, None is a field the record does not have.
+    fn fields(log: &Log) -> Vec<(&'static str, Option<String>)> {
+        let (page_id, slot_id, prev_lsn, undo_next_lsn) = match log.log_type {
+            LogType::Insert(ref insert_log) => (
+                Some(insert_log.page_id),
+                Some(insert_log.slot_id),
+                Some(insert_log.prev_lsn),
+                None,
+            ),
+            LogType::CompensateInsert(ref compensate_insert_log) => (
+                Some(compensate_insert_log.page_id),
+                Some(compensate_insert_log.slot_id),
+                None,
+                Some(compensate_insert_log.next_compenstate_lsn),
+            ),
+            LogType::Delete(ref delete_log) => (
+                Some(delete_log.page_id),
+                Some(delete_log.slot_id),
+                Some(delete_log.prev_lsn),
+                None,
+            ),
+            LogType::CompensateDelete(ref compensate_delete_log) => (
+                Some(compensate_delete_log.page_id),
+                Some(compensate_delete_log.slot_id),
+                None,
+                Some(compensate_delete_log.next_compenstate_lsn),
+            ),
+            _ => (None, None, None, None),
+        };
+        let mut fields = vec![
+            (
+                "transaction_id",
+                log.log_type.transaction_id().map(|id| id.to_string()),
+            ),
+            ("page_id", page_id.map(|id| id.to_string())),
+            ("slot_id", slot_id.map(|id| id.to_string())),
+            ("prev_lsn", prev_lsn.map(|lsn| lsn.to_string())),
+            ("undo_next_lsn", undo_next_lsn.map(|lsn| lsn.to_string())),
+        ];
+        match log.log_type {
+            LogType::Commit(ref commit_log) => {
+                fields.push(("timestamp", Some(commit_log.timestamp.to_string())));
+            }
+            LogType::Insert(ref insert_log) => {
+                fields.push(("tuple", Some(insert_log.tuple.to_string())));
+            }
+            LogType::EndCheckpoint(ref end_checkpoint_log) => {
+                let transaction_table: Vec<String> = end_checkpoint_log
+                    .transaction_table
+                    .iter()
+                    .map(|(id, entry)| format!("[{},{},{}]", id, entry.first_lsn, entry.last_lsn))
+                    .collect();
+                let dirty_page_table: Vec<String> = end_checkpoint_log
+                    .dirty_page_table
+                    .iter()
+                    .map(|(page_id, rec_lsn)| format!("[{},{}]", page_id, rec_lsn))
+                    .collect();
+                fields.extend([
+                    (
+                        "begin_checkpoint_lsn",
+                        Some(end_checkpoint_log.begin_checkpoint_lsn.to_string()),
+                    ),
+                    (
+                        "max_transaction_id",
+                        Some(end_checkpoint_log.max_transaction_id.to_string()),
+                    ),
+                    (
+                        "transaction_table",
+                        Some(format!("[{}]", transaction_table.join(","))),
+                    ),
+                    (
+                        "dirty_page_table",
+                        Some(format!("[{}]", dirty_page_table.join(","))),
+                    ),
+                ]);
+            }
+            _ => {}
+        }
+        fields
+    }
+}
+
+// Checks a data file without running recovery, e.g. after a crash or before
+// restoring a backup, and dumps single pages.
+struct PageFileChecker {
+    file_name: String,
+    page_manager: PageManager,
+}
+
+impl PageFileChecker {
+    const USAGE: &'static str = "07 fsck <data file> [--dump <page id>]";
+
+    fn open(file_name: &str) -> Self {
+        Self {
+            file_name: file_name.to_string(),
+            page_manager: PageManager::load(file_name, false),
+        }
+    }
+    // Returns one message per problem found.
+    fn check(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let file_length = self.page_manager.file.metadata().unwrap().len();
+        if !file_length.is_multiple_of(PAGE_SIZE as u64) {
+            problems.push(format!(
+                "{}: length {} is not a multiple of the page size {}",
+                self.file_name, file_length, PAGE_SIZE
+            ));
+        }
+        for page_id in 0..self.page_manager.next_page_id() {
+            let page = Page::load(self.page_manager.read_page_bytes(page_id).unwrap());
+            problems.extend(
+                PageFileChecker::check_page(page_id, &page)
+                    .into_iter()
+                    .map(|problem| format!("page {}: {}", page_id, problem)),
+            );
+        }
+        problems
+    }
+    fn check_page(page_id: u8, page: &Page) -> Vec<String> {
+        let mut problems = Vec::new();
+        if page.page_id() != page_id {
+            problems.push(format!(
+                "page_id {} does not match its offset",
+                page.page_id()
+            ));
+        }
+        if page.format_version() != Page::FORMAT_VERSION {
+            problems.push(format!(
+                "format_version {} is not {}",
+                page.format_version(),
+                Page::FORMAT_VERSION
+            ));
+        }
+        if page.tuple_length() > Page::MAX_TUPLE_LENGTH {
+            problems.push(format!(
+                "tuple_length {} is larger than {}",
+                page.tuple_length(),
+                Page::MAX_TUPLE_LENGTH
+            ));
+        } else {
+            let free_slots = page.tuple_length()..Page::MAX_TUPLE_LENGTH;
+            if let Some(slot_id) = free_slots.clone().find(|slot_id| {
+                let offset = Page::slot_offset(*slot_id);
+                page.bytes[offset..offset + Page::SLOT_SIZE]
+                    .iter()
+                    .any(|byte| *byte != 0)
+            }) {
+                problems.push(format!(
+                    "free slot {} of {}..{} is not zeroed",
+                    slot_id, free_slots.start, free_slots.end
+                ));
+            }
+        }
+        if page.checksum() != page.compute_checksum() {
+            problems.push(format!(
+                "checksum {:#04x} does not match {:#04x}",
+                page.checksum(),
+                page.compute_checksum()
+            ));
+        }
+        problems
+    }
+    fn dump(&mut self, page_id: u8) {
+        let Some(bytes) = self.page_manager.read_page_bytes(page_id) else {
+            println!("page {}: beyond the end of {}", page_id, self.file_name);
+            return;
+        };
+        let page = Page::load(bytes);
+        println!(
+            "page {} at offset {}",
+            page_id,
+            page_id as usize * PAGE_SIZE
+        );
+        println!("  page_id:      {}", page.page_id());
+        println!("  page_lsn:     {}", page.page_lsn());
+        println!("  format:       {}", page.format_version());
+        println!(
+            "  tuple_length: {} of {}",
+            page.tuple_length(),
+            Page::MAX_TUPLE_LENGTH
+        );
+        println!(
+            "  checksum:     {:#04x} (computed {:#04x})",
+            page.checksum(),
+            page.compute_checksum()
+        );
+        for slot_id in 0..Page::MAX_TUPLE_LENGTH {
+            println!(
+                "  slot {:>2}: {:>3} xmin {:>3} xmax {:>3}{}",
+                slot_id,
+                page.bytes[Page::slot_offset(slot_id)],
+                page.xmin(slot_id),
+                page.xmax(slot_id),
+                if slot_id >= page.tuple_length() {
+                    " (free)"
+                } else if page.is_dead(slot_id) {
+                    " (dead)"
+                } else if page.xmax(slot_id) != INVALID_TRANSACTION_ID {
+                    " (deleted)"
+                } else {
+                    ""
+                }
+            );
+        }
     }
 }
+
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["backup", file_name, log_file_name, backup_dir_name] => {
+            Database::backup_files(file_name, log_file_name, backup_dir_name, None);
+            return;
+        }
+        ["backup", file_name, log_file_name, backup_dir_name, parent_dir_name] => {
+            Database::backup_files(
+                file_name,
+                log_file_name,
+                backup_dir_name,
+                Some(parent_dir_name),
+            );
+            return;
+        }
+        ["merge", ref backup_dir_names @ .., output_dir_name] if !backup_dir_names.is_empty() => {
+            Database::merge_backups(backup_dir_names, output_dir_name);
+            return;
+        }
+        ["waldump", path, ref options @ ..] => {
+            match WalDump::parse(options) {
+                Some(wal_dump) => wal_dump.run(path),
+                None => {
+                    eprintln!("usage: {}", WalDump::USAGE);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        ["fsck", file_name] => {
+            let problems = PageFileChecker::open(file_name).check();
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+            return;
+        }
+        ["fsck", file_name, "--dump", page_id] => {
+            let Ok(page_id) = page_id.parse() else {
+                eprintln!("usage: {}", PageFileChecker::USAGE);
+                std::process::exit(1);
+            };
+            PageFileChecker::open(file_name).dump(page_id);
+            return;
+        }
+        ["decode", log_file_name, slot_name] => {
+            let position =
+                LogicalPosition::read(log_file_name, slot_name).unwrap_or(LogicalPosition {
+                    restart_lsn: *LogManager::segment_lsns(log_file_name)
+                        .first()
+                        .unwrap_or(&0),
+                    confirmed_lsn: None,
+                });
+            let mut last_position = None;
+            let logs = LogReader::open(log_file_name);
+            let decoder = match LogicalDecoder::new(log_file_name, logs, position) {
+                Ok(decoder) => decoder,
+                Err(error) => {
+                    eprintln!("{:?}", error);
+                    std::process::exit(1);
+                }
+            };
+            for transaction in decoder {
+                println!("{}", transaction.to_json());
+                last_position = Some(transaction.position);
+            }
+            if let Some(position) = last_position {
+                position.write(log_file_name, slot_name);
+            }
+            return;
+        }
+        ["primary", file_name, log_file_name, address] => {
+            primary(file_name, log_file_name, address);
+            return;
+        }
+        ["standby", file_name, log_file_name, primary_address] => {
+            standby(file_name, log_file_name, primary_address);
+            return;
+        }
+        [] => {}
+        _ => {
+            eprintln!(
+                "usage: 07 [backup <data file> <log dir> <backup dir> [<parent backup dir>]]"
+            );
+            eprintln!(
+                "       07 [merge <full backup dir> <incremental backup dir>... <output dir>]"
+            );
+            eprintln!("       {}", WalDump::USAGE);
+            eprintln!("       {}", PageFileChecker::USAGE);
+            eprintln!("       07 [decode <log dir> <slot name>]");
+            eprintln!("       07 [primary <data file> <log dir> <listen address>]");
+            eprintln!("       07 [standby <data file> <log dir> <primary address>]");
+            std::process::exit(1);
+        }
+    }
+
     println!("<prev_example>");
     prev_example();
     println!("<concurrent_example>");
     concurrent_example();
     println!("<concurrent_isolation_example>");
     concurrent_isolation_example();
+    println!("<direct_io_example>");
+    direct_io_example();
+    println!("<resize_buffer_pool_example>");
+    resize_buffer_pool_example();
+    println!("<buffer_pool_statistics_example>");
+    buffer_pool_statistics_example();
+    println!("<group_commit_example>");
+    group_commit_example();
+    println!("<torn_log_example>");
+    torn_log_example();
+    println!("<checkpoint_example>");
+    checkpoint_example();
+    println!("<segmented_log_example>");
+    segmented_log_example();
+    println!("<crash_during_recovery_example>");
+    crash_during_recovery_example();
+    println!("<point_in_time_recovery_example>");
+    point_in_time_recovery_example();
+    println!("<online_backup_example>");
+    online_backup_example();
+    println!("<incremental_backup_example>");
+    incremental_backup_example();
+    println!("<waldump_example>");
+    waldump_example();
+    println!("<page_file_checker_example>");
+    page_file_checker_example();
+    println!("<streaming_replication_example>");
+    streaming_replication_example();
+    println!("<hot_standby_example>");
+    hot_standby_example();
+    println!("<synchronous_replication_example>");
+    synchronous_replication_example();
+    println!("<logical_decoding_example>");
+    logical_decoding_example();
+    println!("<deadlock_detection_example>");
+    deadlock_detection_example();
+    println!("<lock_wait_example>");
+    lock_wait_example();
+    println!("<multi_granularity_locking_example>");
+    multi_granularity_locking_example();
+    println!("<lock_escalation_example>");
+    lock_escalation_example();
+    println!("<phantom_protection_example>");
+    phantom_protection_example();
+    println!("<lock_view_example>");
+    lock_view_example();
+    println!("<fair_lock_queue_example>");
+    fair_lock_queue_example();
+    println!("<snapshot_isolation_example>");
+    snapshot_isolation_example();
+}
+
+// Opens a new database that streams its log to standbys and inserts every
+// number read from stdin in its own transaction.
+fn primary(file_name: &str, log_file_name: &str, address: &str) {
+    let database = Database::init(file_name, log_file_name, 3);
+    println!(
+        "primary listening on {}",
+        database.start_wal_sender(address)
+    );
+    for line in std::io::stdin().lock().lines() {
+        let Ok(tuple) = line.unwrap().trim().parse() else {
+            eprintln!("expected a number from 0 to 255");
+            continue;
+        };
+        let mut transaction = database.begin();
+        database.insert(&mut transaction, tuple);
+        database.commit(&mut transaction);
+        println!("inserted {}", tuple);
+    }
+}
+
+// Replays the primary's log until stdin reads "promote" or closes. "read"
+// prints the values a read-only transaction sees.
+fn standby(file_name: &str, log_file_name: &str, primary_address: &str) {
+    let database = match Database::standby(
+        file_name,
+        log_file_name,
+        DatabaseOptions::new(3),
+        primary_address,
+    ) {
+        Ok(database) => database,
+        Err(error) => {
+            eprintln!("cannot connect to {}: {}", primary_address, error);
+            return;
+        }
+    };
+    println!("standby replaying from {}", primary_address);
+    for line in std::io::stdin().lock().lines() {
+        match line.unwrap().trim() {
+            "promote" => break,
+            "read" => {
+                let mut transaction = database.begin();
+                println!("values: {:?}", database.read_all(&mut transaction));
+                database.commit(&mut transaction);
+            }
+            _ => println!("replayed up to lsn {:?}", database.replayed_lsn()),
+        }
+    }
+    database.promote();
+    let mut transaction = database.begin();
+    println!(
+        "promoted, values: {:?}",
+        database.read_all(&mut transaction)
+    );
+}
+
+fn prev_example() {
+    let database = Database::init("db", "log", 10);
+
+    println!("______________________");
+    let mut transaction = database.begin();
+    println!("Start transaction");
+    database.insert(&mut transaction, 10);
+    println!("Insert 10");
+    database.insert(&mut transaction, 20);
+    println!("Insert 20");
+    database.commit(&mut transaction);
+    println!("Commit\n");
+
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}\n", values);
+    database.commit(&mut transaction);
+
+    let mut transaction = database.begin();
+    println!("Start transaction");
+    database.insert(&mut transaction, 30);
+    println!("Insert 30");
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
+    database.abort(&mut transaction);
+    println!("Abort\n");
+
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}\n", values);
+    database.commit(&mut transaction);
+
+    let mut transaction = database.begin();
+    println!("Start transaction");
+    database.insert(&mut transaction, 40);
+    println!("Insert 40");
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
+    println!("Not commit and shutdown.\n");
+
+    println!("______________________");
+    println!("Open existing database.");
+    let database = Database::load("db", "log", 10);
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
+}
+
+fn concurrent_example() {
+    let database = Database::init("db", "log", 10);
+
+    println!("______________________");
+    let mut transaction1 = database.begin();
+    println!("Start transaction1");
+    let mut transaction2 = database.begin();
+    println!("Start transaction2");
+
+    database.insert(&mut transaction1, 10);
+    println!("Insert 10 by transaction1");
+    database.insert(&mut transaction2, 20);
+    println!("Insert 20 by transaction2");
+
+    database.commit(&mut transaction1);
+    println!("Commit transaction1");
+    println!("Not commit transaction2 and shutdown.\n");
+
+    let database = Database::load("db", "log", 10);
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
+}
+
+fn concurrent_isolation_example() {
+    let database = Arc::new(Database::init("db", "log", 10));
+    database.set_default_isolation_level(IsolationLevel::RepeatableRead);
+
+    println!("______________________");
+    let database_clone = database.clone();
+    thread::spawn(move || {
+        let mut transaction1 = database_clone.begin();
+        println!("Start transaction1");
+        database_clone.insert(&mut transaction1, 10);
+        println!("Insert 10 by transaction1");
+        println!("Sleep 3 seconds(transaction1)");
+        thread::sleep(Duration::from_secs(3));
+        database_clone.commit(&mut transaction1);
+        println!("Commit transaction1");
+    });
+    let mut transaction2 = database.begin();
+    println!("Start transaction2");
+    thread::sleep(Duration::from_secs(1));
+    println!("Try to read all by transaction2");
+    let values = database.read_all(&mut transaction2);
+    println!("Read all by transaction2");
+    println!("  values: {:?}", values);
+}
+
+fn direct_io_example() {
+    let database = Database::init_with_options("db", "log", DatabaseOptions::new(3).direct_io());
+
+    println!("______________________");
+    let mut transaction = database.begin();
+    println!("Start transaction with direct I/O");
+    for i in 0..30 {
+        database.insert(&mut transaction, i);
+    }
+    println!("Insert 0..30");
+    database.commit(&mut transaction);
+    println!("Commit");
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}\n", values);
+    database.commit(&mut transaction);
+
+    println!("Open existing database without direct I/O.");
+    let database = Database::load("db", "log", 3);
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
+}
+
+fn resize_buffer_pool_example() {
+    let database = Database::init("db", "log", 10);
+
+    println!("______________________");
+    let mut transaction = database.begin();
+    for i in 0..50 {
+        database.insert(&mut transaction, i);
+    }
+    println!("Insert 0..50");
+    database.commit(&mut transaction);
+    println!("Commit");
+    println!("{:?}", database);
+
+    let frame_length = database.resize_buffer_pool(2);
+    println!("Resize buffer pool to 2 (frames: {})", frame_length);
+    println!("{:?}", database);
+
+    let frame_length = database.resize_buffer_pool(5);
+    println!("Resize buffer pool to 5 (frames: {})", frame_length);
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
+    database.commit(&mut transaction);
+    println!("{:?}", database);
+}
+
+fn buffer_pool_statistics_example() {
+    let database = Database::init("db", "log", 3);
+
+    println!("______________________");
+    let mut transaction = database.begin();
+    for i in 0..40 {
+        database.insert(&mut transaction, i);
+    }
+    println!("Insert 0..40");
+    database.commit(&mut transaction);
+    println!("Commit");
+    let mut transaction = database.begin();
+    database.read_all(&mut transaction);
+    println!("Read all");
+    database.commit(&mut transaction);
+
+    for view in [
+        SystemView::BufferPoolStatistics,
+        SystemView::BufferPoolFrames,
+    ] {
+        println!("{}", view.columns().join(" | "));
+        for row in database.read_system_view(view) {
+            println!("  {}", row.join(" | "));
+        }
+    }
+}
+
+fn group_commit_example() {
+    const THREAD_COUNT: u8 = 8;
+    const TRANSACTIONS_PER_THREAD: u8 = 8;
+
+    println!("______________________");
+    for commit_delay in [Duration::ZERO, Duration::from_millis(2)] {
+        let database = Arc::new(Database::init("db", "log", 10));
+        database.set_commit_delay(commit_delay);
+        let started_at = std::time::Instant::now();
+        let handles: Vec<_> = (0..THREAD_COUNT)
+            .map(|thread_index| {
+                let database = database.clone();
+                thread::spawn(move || {
+                    for i in 0..TRANSACTIONS_PER_THREAD {
+                        let mut transaction = database.begin();
+                        database
+                            .insert(&mut transaction, thread_index * TRANSACTIONS_PER_THREAD + i);
+                        database.commit(&mut transaction);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let elapsed = started_at.elapsed();
+        let flush_count = database.log_manager.read().unwrap().flush_count();
+        println!(
+            "commit_delay: {:?}, commits: {}, fsyncs: {}, commits/sec: {:.0}",
+            commit_delay,
+            THREAD_COUNT as usize * TRANSACTIONS_PER_THREAD as usize,
+            flush_count,
+            (THREAD_COUNT as f64 * TRANSACTIONS_PER_THREAD as f64) / elapsed.as_secs_f64()
+        );
+        drop(database);
+
+        let database = Database::load("db", "log", 10);
+        let mut transaction = database.begin();
+        let mut values = database.read_all(&mut transaction);
+        values.sort();
+        println!("Read all after reopen");
+        println!("  durable values: {}", values.len());
+        assert_eq!(
+            values,
+            (0..THREAD_COUNT * TRANSACTIONS_PER_THREAD).collect::<Vec<_>>(),
+            "every committed transaction must be durable"
+        );
+    }
+}
+
+fn torn_log_example() {
+    let database = Database::init("db", "log", 10);
+
+    println!("______________________");
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 10);
+    database.insert(&mut transaction, 20);
+    database.commit(&mut transaction);
+    println!("Insert 10, 20 and commit");
+    let second_transaction_lsn = database.log_manager.read().unwrap().current_lsn.unwrap();
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 30);
+    database.commit(&mut transaction);
+    println!("Insert 30 and commit");
+    let log_length = database.log_manager.read().unwrap().size();
+    drop(database);
+
+    let last_segment_path =
+        LogManager::segment_path("log", *LogManager::segment_lsns("log").last().unwrap());
+    let file = OpenOptions::new()
+        .write(true)
+        .open(last_segment_path)
+        .unwrap();
+    file.set_len(file.metadata().unwrap().len() - 3).unwrap();
+    println!("Crash in the middle of writing the last commit record.\n");
+
+    println!("Open existing database.");
+    // Loading the log drops the torn record, before recovery appends anything.
+    println!(
+        "  log length: {} -> {}",
+        log_length,
+        LogManager::load("log").size()
+    );
+    let database = Database::load("db", "log", 10);
+    for log in database
+        .log_manager
+        .write()
+        .unwrap()
+        .read()
+        .iter()
+        .filter(|log| log.lsn >= second_transaction_lsn)
+    {
+        println!("  {:?}", log);
+    }
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
+    database.insert(&mut transaction, 40);
+    database.commit(&mut transaction);
+    println!("Insert 40 and commit\n");
+    drop(database);
+
+    println!("Open existing database.");
+    let database = Database::load("db", "log", 10);
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
+}
+
+fn checkpoint_example() {
+    let database = Database::init("db", "log", 10);
+    database.start_checkpointer(Duration::from_millis(50));
+
+    println!("______________________");
+    for i in 0..5 {
+        let mut transaction = database.begin();
+        database.insert(&mut transaction, i * 10);
+        database.insert(&mut transaction, i * 10 + 1);
+        database.commit(&mut transaction);
+        println!(
+            "Insert {}, {} and commit (log length: {})",
+            i * 10,
+            i * 10 + 1,
+            database.log_manager.read().unwrap().size()
+        );
+        thread::sleep(Duration::from_millis(80));
+    }
+    database.checkpoint().unwrap();
+    println!(
+        "Checkpoint (log length: {})",
+        database.log_manager.read().unwrap().size()
+    );
+    thread::sleep(Duration::from_millis(200));
+    println!(
+        "Stay idle, the checkpointer skips (log length: {})",
+        database.log_manager.read().unwrap().size()
+    );
+    let mut transaction = database.begin();
+    println!("Start transaction");
+    database.insert(&mut transaction, 99);
+    println!("Insert 99");
+    thread::sleep(Duration::from_millis(80));
+    println!(
+        "Not commit (log length: {})",
+        database.log_manager.read().unwrap().size()
+    );
+    println!(
+        "Stop the checkpointer and shutdown: {:?}\n",
+        database.stop_checkpointer()
+    );
+    drop(transaction);
+    drop(database);
+
+    println!("Open existing database.");
+    let database = Database::load("db", "log", 10);
+    let first_lsn = database.log_manager.write().unwrap().read()[0].lsn;
+    println!("  first lsn in log: {}", first_lsn);
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
+}
+
+fn segmented_log_example() {
+    let database = Database::init("db", "log", 10);
+
+    println!("______________________");
+    for i in 0..12 {
+        let mut transaction = database.begin();
+        database.insert(&mut transaction, i);
+        database.commit(&mut transaction);
+    }
+    println!("Insert 0..12, one transaction each");
+    println!("  segments: {:?}", LogManager::segment_lsns("log"));
+    database.checkpoint().unwrap();
+    println!("Checkpoint");
+    println!("  segments: {:?}", LogManager::segment_lsns("log"));
+    drop(database);
+
+    println!("Open existing database.");
+    let database = Database::load("db", "log", 10);
+    let lsns: Vec<u8> = LogReader::open("log").map(|log| log.lsn).collect();
+    println!("  lsns: {:?}", lsns);
+    database.set_log_segment_size(64);
+    for i in 12..16 {
+        let mut transaction = database.begin();
+        database.insert(&mut transaction, i);
+        database.commit(&mut transaction);
+    }
+    println!("Set the segment size to 64 bytes and insert 12..16, one transaction each");
+    println!("  segments: {:?}", LogManager::segment_lsns("log"));
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
+}
+
+fn crash_during_recovery_example() {
+    let database = Database::init("db", "log", 10);
+
+    println!("______________________");
+    let mut transaction1 = database.begin();
+    database.insert(&mut transaction1, 10);
+    database.commit(&mut transaction1);
+    println!("Insert 10 by transaction1 and commit");
+    let mut transaction2 = database.begin();
+    database.insert(&mut transaction2, 20);
+    database.insert(&mut transaction2, 30);
+    database.insert(&mut transaction2, 40);
+    println!("Insert 20, 30, 40 by transaction2");
+    let mut transaction3 = database.begin();
+    database.commit(&mut transaction3);
+    println!("Commit empty transaction3 (flushes the log of transaction2)");
+    println!("Not commit transaction2 and shutdown.\n");
+    drop(transaction2);
+    drop(database);
+
+    println!("Recover, but crash after the first compensation.");
+    {
+        let log_manager = Arc::new(RwLock::new(LogManager::load("log")));
+        let buffer_pool_manager = Arc::new(RwLock::new(BufferPoolManager::new(
+            PageManager::load("db", false),
+            10,
+        )));
+        let mut recovery_manager =
+            RecoveryManager::new(log_manager.clone(), buffer_pool_manager.clone());
+        let logs = recovery_manager.replay();
+        // The first undo step compensates the last insert of transaction2.
+        let mut undo_next_lsns = recovery_manager.undo_next_lsns();
+        recovery_manager.undo_step(&logs, &mut undo_next_lsns);
+        // The CLR and the compensated page reach the disk before the crash.
+        LogManager::flush_all(&log_manager);
+        buffer_pool_manager.write().unwrap().flush_dirty_pages();
+    }
+    for log in LogManager::load("log").read() {
+        println!("  {:?}", log);
+    }
+
+    println!("Recover again.");
+    let database = Database::load("db", "log", 10);
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
+    database.commit(&mut transaction);
+    drop(database);
+    for log in LogManager::load("log").read() {
+        println!("  {:?}", log);
+    }
+
+    println!("Open existing database.");
+    let database = Database::load("db", "log", 10);
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", values);
 }
 
-fn prev_example() {
+fn point_in_time_recovery_example() {
     let database = Database::init("db", "log", 10);
+    database.enable_wal_archive("archive");
 
     println!("______________________");
     let mut transaction = database.begin();
-    println!("Start transaction");
     database.insert(&mut transaction, 10);
-    println!("Insert 10");
     database.insert(&mut transaction, 20);
-    println!("Insert 20");
     database.commit(&mut transaction);
-    println!("Commit\n");
+    println!("Insert 10, 20 and commit");
+    database.base_backup("backup").unwrap();
+    println!("Take a base backup");
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 30);
+    database.commit(&mut transaction);
+    let good_transaction_id = transaction.transaction_id;
+    println!("Insert 30 and commit (transaction{})", good_transaction_id);
+    thread::sleep(Duration::from_millis(10));
+    let before_bad_write = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    thread::sleep(Duration::from_millis(10));
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 99);
+    database.commit(&mut transaction);
+    println!("Insert 99 by mistake and commit");
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 40);
+    database.commit(&mut transaction);
+    println!("Insert 40 and commit");
+    database.switch_log_segment();
+    println!(
+        "  archived segments: {:?}",
+        LogManager::segment_lsns("archive")
+    );
+    drop(database);
+
+    for (name, target) in [
+        (
+            "timestamp before the mistake",
+            RecoveryTarget::Timestamp(before_bad_write),
+        ),
+        (
+            "commit of the last good transaction",
+            RecoveryTarget::TransactionId(good_transaction_id),
+        ),
+        ("latest archived lsn", RecoveryTarget::Lsn(u8::MAX)),
+    ] {
+        println!("Restore to the {}", name);
+        let database = Database::restore(
+            "restored_db",
+            "restored_log",
+            DatabaseOptions::new(10),
+            RestoreOptions {
+                base_backup_dir_name: "backup",
+                archive_dir_name: "archive",
+                target,
+            },
+        )
+        .unwrap();
+        let mut transaction = database.begin();
+        let values = database.read_all(&mut transaction);
+        println!("Read all");
+        println!("  values: {:?}", values);
+    }
+    println!("Restore to lsn 0, which is before the end of the base backup");
+    let result = Database::restore(
+        "restored_db",
+        "restored_log",
+        DatabaseOptions::new(10),
+        RestoreOptions {
+            base_backup_dir_name: "backup",
+            archive_dir_name: "archive",
+            target: RecoveryTarget::Lsn(0),
+        },
+    );
+    println!("  error: {:?}", result.err());
+}
 
+fn online_backup_example() {
+    let database = Arc::new(Database::init("db", "log", 3));
+
+    println!("______________________");
     let mut transaction = database.begin();
-    let values = database.read_all(&mut transaction);
-    println!("Read all");
-    println!("  values: {:?}\n", values);
+    for i in 0..20 {
+        database.insert(&mut transaction, i);
+    }
+    database.commit(&mut transaction);
+    println!("Insert 0..20 and commit");
+
+    let database_clone = database.clone();
+    let writer = thread::spawn(move || {
+        let mut transaction = database_clone.begin();
+        for i in 20..40 {
+            database_clone.insert(&mut transaction, i);
+            thread::sleep(Duration::from_millis(1));
+        }
+        database_clone.commit(&mut transaction);
+    });
+    database.base_backup("backup").unwrap();
+    println!("Take a base backup while inserting 20..40");
+    Database::backup_files("db", "log", "file_backup", None);
+    println!("Take a base backup from the files while inserting 20..40");
+    writer.join().unwrap();
+    drop(database);
+
+    for backup_dir_name in ["backup", "file_backup"] {
+        println!("Open {} with Database::load", backup_dir_name);
+        let database = Database::load(
+            &format!("{}/{}", backup_dir_name, BackupLabel::DATA_FILE_NAME),
+            &format!("{}/{}", backup_dir_name, BackupLabel::LOG_DIR_NAME),
+            3,
+        );
+        let mut transaction = database.begin();
+        let values = database.read_all(&mut transaction);
+        println!("Read all");
+        println!("  values: {:?}", values);
+    }
+}
+
+fn incremental_backup_example() {
+    let database = Database::init("db", "log", 3);
+
+    println!("______________________");
+    let mut transaction = database.begin();
+    for i in 0..20 {
+        database.insert(&mut transaction, i);
+    }
     database.commit(&mut transaction);
+    database.base_backup("full_backup").unwrap();
+    println!("Insert 0..20, commit and take a full backup");
+
+    for (i, backup_dir_name) in ["incremental_backup_1", "incremental_backup_2"]
+        .into_iter()
+        .enumerate()
+    {
+        let mut transaction = database.begin();
+        for j in 0..5 {
+            database.insert(&mut transaction, 20 + i as u8 * 5 + j);
+        }
+        database.commit(&mut transaction);
+        let parent_dir_name = ["full_backup", "incremental_backup_1"][i];
+        database
+            .incremental_backup(backup_dir_name, parent_dir_name)
+            .unwrap();
+        println!(
+            "Insert {}..{}, commit and take {}",
+            20 + i * 5,
+            25 + i * 5,
+            backup_dir_name
+        );
+    }
+    for backup_dir_name in [
+        "full_backup",
+        "incremental_backup_1",
+        "incremental_backup_2",
+    ] {
+        let label = BackupLabel::read(backup_dir_name);
+        let data_length =
+            fs::metadata(Path::new(backup_dir_name).join(BackupLabel::DATA_FILE_NAME))
+                .unwrap()
+                .len();
+        println!(
+            "  {}: start_lsn {}, end_lsn {}, incremental_from {:?}, pages {}",
+            backup_dir_name,
+            label.start_lsn,
+            label.end_lsn,
+            label.incremental_from,
+            data_length / PAGE_SIZE as u64
+        );
+    }
+    drop(database);
 
+    Database::merge_backups(
+        &[
+            "full_backup",
+            "incremental_backup_1",
+            "incremental_backup_2",
+        ],
+        "merged_backup",
+    );
+    println!("Merge the full backup with both incremental backups");
+    let database = Database::load("merged_backup/data", "merged_backup/log", 3);
     let mut transaction = database.begin();
-    println!("Start transaction");
-    database.insert(&mut transaction, 30);
-    println!("Insert 30");
     let values = database.read_all(&mut transaction);
     println!("Read all");
     println!("  values: {:?}", values);
+}
+
+fn waldump_example() {
+    let database = Database::init("db", "log", 10);
+
+    println!("______________________");
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 10);
+    database.insert(&mut transaction, 20);
+    database.commit(&mut transaction);
+    println!("Insert 10, 20 and commit");
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 30);
     database.abort(&mut transaction);
-    println!("Abort\n");
+    println!(
+        "Insert 30 and abort (transaction{})",
+        transaction.transaction_id
+    );
+    drop(database);
+
+    println!("waldump log");
+    WalDump::parse(&[]).unwrap().run("log");
+    println!("waldump log --transaction 1");
+    WalDump::parse(&["--transaction", "1"]).unwrap().run("log");
+    println!("waldump log --start 1 --end 2 --json");
+    WalDump::parse(&["--start", "1", "--end", "2", "--json"])
+        .unwrap()
+        .run("log");
+}
 
+fn page_file_checker_example() {
+    let database = Database::init("db", "log", 3);
+
+    println!("______________________");
     let mut transaction = database.begin();
-    let values = database.read_all(&mut transaction);
-    println!("Read all");
-    println!("  values: {:?}\n", values);
+    for i in 0..15 {
+        database.insert(&mut transaction, i);
+    }
     database.commit(&mut transaction);
+    database.checkpoint().unwrap();
+    drop(database);
+    println!("Insert 0..15, commit and checkpoint");
+
+    let mut checker = PageFileChecker::open("db");
+    println!("Check db");
+    println!("  problems: {:?}", checker.check());
+    checker.dump(1);
+
+    let mut file = OpenOptions::new().write(true).open("db").unwrap();
+    file.seek(SeekFrom::Start(PAGE_SIZE as u64 + 2)).unwrap();
+    file.write_all(&[13]).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write_all(&[9]).unwrap();
+    println!("Overwrite page_id of page 0 and tuple_length of page 1");
+    println!("Check db");
+    for problem in PageFileChecker::open("db").check() {
+        println!("  {}", problem);
+    }
 
+    let database = Database::load("db", "log", 3);
+    println!("Open db, recovery rebuilds the corrupted pages from the log");
+    println!("  (the checkpoint truncated the inserts of 0..6 from the log)");
+    println!("  corrupted pages: {:?}", database.corrupted_pages());
     let mut transaction = database.begin();
-    println!("Start transaction");
-    database.insert(&mut transaction, 40);
-    println!("Insert 40");
-    let values = database.read_all(&mut transaction);
+    println!("Read all");
+    println!("  values: {:?}", database.read_all(&mut transaction));
+}
+
+fn streaming_replication_example() {
+    let primary = Database::init("db", "log", 3);
+    let address = primary.start_wal_sender("127.0.0.1:0");
+
+    println!("______________________");
+    let mut transaction = primary.begin();
+    for i in 0..5 {
+        primary.insert(&mut transaction, i);
+    }
+    primary.commit(&mut transaction);
+    primary.base_backup("standby").unwrap();
+    let standby = Database::standby(
+        &format!("standby/{}", BackupLabel::DATA_FILE_NAME),
+        &format!("standby/{}", BackupLabel::LOG_DIR_NAME),
+        DatabaseOptions::new(3),
+        &address.to_string(),
+    )
+    .unwrap();
+    println!("Insert 0..5, commit and start a standby from a base backup");
+
+    let mut transaction = primary.begin();
+    for i in 5..20 {
+        primary.insert(&mut transaction, i);
+    }
+    primary.commit(&mut transaction);
+    let mut in_flight_transaction = primary.begin();
+    primary.insert(&mut in_flight_transaction, 99);
+    primary.checkpoint().unwrap();
+    println!("Insert 5..20 and commit, insert 99 without commit and checkpoint");
+
+    let primary_lsn = primary.log_manager.read().unwrap().last_lsn();
+    while standby.log_manager.read().unwrap().last_lsn() < primary_lsn {
+        thread::sleep(Duration::from_millis(1));
+    }
+    println!("  standby replayed up to lsn {}", primary_lsn.unwrap());
+    println!("  replication error: {:?}", standby.replication_error());
+    drop(in_flight_transaction);
+    drop(primary);
+    println!("Stop the primary");
+
+    standby.promote();
+    println!("Promote the standby");
+    let mut transaction = standby.begin();
+    standby.insert(&mut transaction, 20);
+    standby.commit(&mut transaction);
+    println!("Insert 20 and commit on the new primary");
+    let mut transaction = standby.begin();
+    let values = standby.read_all(&mut transaction);
     println!("Read all");
     println!("  values: {:?}", values);
-    println!("Not commit and shutdown.\n");
+}
+
+fn hot_standby_example() {
+    let primary = Database::init("db", "log", 3);
+    let address = primary.start_wal_sender("127.0.0.1:0");
+    primary.base_backup("standby").unwrap();
+    let standby = Database::standby(
+        &format!("standby/{}", BackupLabel::DATA_FILE_NAME),
+        &format!("standby/{}", BackupLabel::LOG_DIR_NAME),
+        DatabaseOptions::new(3),
+        &address.to_string(),
+    )
+    .unwrap();
+    let wait_for_replay = |primary: &Database| {
+        let primary_lsn = primary.log_manager.read().unwrap().last_lsn();
+        while standby.replayed_lsn() < primary_lsn {
+            thread::sleep(Duration::from_millis(1));
+        }
+    };
 
     println!("______________________");
-    println!("Open existing database.");
-    let database = Database::load("db", "log", 10);
+    let mut transaction = primary.begin();
+    for i in 0..15 {
+        primary.insert(&mut transaction, i);
+    }
+    primary.commit(&mut transaction);
+    let mut in_flight_transaction = primary.begin();
+    primary.insert(&mut in_flight_transaction, 99);
+    let mut transaction = primary.begin();
+    primary.insert(&mut transaction, 15);
+    primary.commit(&mut transaction);
+    wait_for_replay(&primary);
+    println!("Insert 0..16 and commit on the primary, insert 99 without commit");
+
+    let mut read_transaction = standby.begin();
+    println!(
+        "Begin a read-only transaction on the standby at lsn {:?}",
+        read_transaction.snapshot.as_ref().unwrap().lsn
+    );
+    println!("  values: {:?}", standby.read_all(&mut read_transaction));
+
+    primary.commit(&mut in_flight_transaction);
+    let mut transaction = primary.begin();
+    primary.insert(&mut transaction, 16);
+    primary.commit(&mut transaction);
+    thread::sleep(Duration::from_millis(50));
+    println!("Commit 99, insert 16 and commit on the primary");
+    println!("Read all in the same read-only transaction");
+    println!("  values: {:?}", standby.read_all(&mut read_transaction));
+    standby.commit(&mut read_transaction);
+
+    wait_for_replay(&primary);
+    let mut read_transaction = standby.begin();
+    println!(
+        "Begin a read-only transaction on the standby at lsn {:?}",
+        read_transaction.snapshot.as_ref().unwrap().lsn
+    );
+    println!("  values: {:?}", standby.read_all(&mut read_transaction));
+    standby.commit(&mut read_transaction);
+
+    standby.set_max_standby_delay(Duration::from_millis(100));
+    let mut read_transaction = standby.begin();
+    println!("Set max_standby_delay to 100ms and begin a read-only transaction");
+    let mut transaction = primary.begin();
+    primary.insert(&mut transaction, 17);
+    primary.commit(&mut transaction);
+    wait_for_replay(&primary);
+    println!("Insert 17 and commit on the primary, replay cancels the reader after 100ms");
+    println!("Read all in the same read-only transaction");
+    println!("  {:?}", standby.try_read_all(&mut read_transaction));
+}
+
+fn synchronous_replication_example() {
+    let primary = Database::init("db", "log", 3);
+    let address = primary.start_wal_sender("127.0.0.1:0");
+    primary.base_backup("standby").unwrap();
+    let open_standby = || {
+        Database::standby(
+            &format!("standby/{}", BackupLabel::DATA_FILE_NAME),
+            &format!("standby/{}", BackupLabel::LOG_DIR_NAME),
+            DatabaseOptions::new(3),
+            &address.to_string(),
+        )
+        .unwrap()
+    };
+    let standby = open_standby();
+    primary.set_synchronous_standbys(1, Duration::from_millis(200));
+    let commit = |tuple: u8| {
+        let start = Instant::now();
+        let mut transaction = primary.begin();
+        primary.insert(&mut transaction, tuple);
+        primary.commit(&mut transaction);
+        let sync_replication = primary.log_manager.read().unwrap().sync_replication.clone();
+        println!(
+            "Insert {} and commit in {}ms, degraded to async: {}",
+            tuple,
+            start.elapsed().as_millis(),
+            sync_replication.is_degraded()
+        );
+        transaction.prev_lsn()
+    };
+
+    println!("______________________");
+    println!("Wait for 1 standby, for at most 200ms");
+    let commit_lsn = commit(0);
+    println!(
+        "  standby has flushed the commit record: {}",
+        standby.log_manager.read().unwrap().last_lsn() >= Some(commit_lsn)
+    );
+    drop(standby);
+    println!("Stop the standby");
+    commit(1);
+    commit(2);
+    let standby = open_standby();
+    let sync_replication = primary.log_manager.read().unwrap().sync_replication.clone();
+    while sync_replication.is_degraded() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    println!("Restart the standby and wait until it has caught up");
+    commit(3);
+    let commit_lsn = commit(4);
+    while standby.replayed_lsn() < Some(commit_lsn) {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let mut transaction = standby.begin();
+    println!("Read all on the standby");
+    println!("  values: {:?}", standby.read_all(&mut transaction));
+}
+
+fn logical_decoding_example() {
+    let database = Arc::new(Database::init("db", "log", 3));
+    let changes = database.logical_changes("cdc").unwrap();
+    let consumer = thread::spawn(move || changes.take(2).collect::<Vec<_>>());
+
+    println!("______________________");
+    let mut transaction_1 = database.begin();
+    database.insert(&mut transaction_1, 1);
+    database.insert(&mut transaction_1, 2);
+    let mut transaction_2 = database.begin();
+    database.insert(&mut transaction_2, 10);
+    database.commit(&mut transaction_2);
+    database.commit(&mut transaction_1);
+    let mut transaction_3 = database.begin();
+    database.insert(&mut transaction_3, 20);
+    database.abort(&mut transaction_3);
+    println!("Insert 1, 2 in tx 0, insert 10 in tx 1, commit tx 1, commit tx 0");
+    println!("Insert 20 in tx 2 and abort");
+    println!("Subscribe to slot cdc");
+    let committed_transactions = consumer.join().unwrap();
+    for committed_transaction in &committed_transactions {
+        println!("  {}", committed_transaction.to_json());
+    }
+
+    database.confirm_logical_position("cdc", committed_transactions[0].position);
+    println!("Confirm only the first transaction");
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 30);
+    database.commit(&mut transaction);
+    println!("Insert 30 in tx 3 and commit");
+    database.checkpoint().unwrap();
+    println!("Checkpoint");
+    println!("Subscribe to slot cdc again");
+    for committed_transaction in database.logical_changes("cdc").unwrap().take(2) {
+        println!("  {}", committed_transaction.to_json());
+    }
+}
+
+fn deadlock_detection_example() {
+    let database = Arc::new(Database::init("db", "log", 3));
+    database.set_default_isolation_level(IsolationLevel::RepeatableRead);
+
+    println!("______________________");
+    let mut transaction_0 = database.begin();
+    database.insert(&mut transaction_0, 1);
+    let mut transaction_1 = database.begin();
+    database.insert(&mut transaction_1, 2);
+    println!("Insert 1 in tx 0, insert 2 in tx 1");
+
+    let database_clone = database.clone();
+    let reader = thread::spawn(move || {
+        let values = database_clone.try_read_all(&mut transaction_0);
+        database_clone.commit(&mut transaction_0);
+        values
+    });
+    wait_until_waiting(&database, 0);
+    println!("Read all in tx 0, which waits for tx 1");
+    println!("Read all in tx 1, which waits for tx 0");
+    println!("  tx 1: {:?}", database.try_read_all(&mut transaction_1));
+    println!("  tx 0: {:?}", reader.join().unwrap());
+
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 2);
+    database.commit(&mut transaction);
+    println!("Retry tx 1 as tx 2: insert 2 and commit");
     let mut transaction = database.begin();
     let values = database.read_all(&mut transaction);
     println!("Read all");
     println!("  values: {:?}", values);
 }
 
-fn concurrent_example() {
-    let database = Database::init("db", "log", 10);
+fn lock_wait_example() {
+    let database = Database::init("db", "log", 3);
+    database.set_default_isolation_level(IsolationLevel::RepeatableRead);
 
     println!("______________________");
-    let mut transaction1 = database.begin();
-    println!("Start transaction1");
-    let mut transaction2 = database.begin();
-    println!("Start transaction2");
+    let mut transaction_0 = database.begin();
+    for tuple in 1..=3 {
+        database.insert(&mut transaction_0, tuple);
+    }
+    database.commit(&mut transaction_0);
+    println!("Insert 1, 2, 3 in tx 0 and commit");
+    let mut transaction_1 = database.begin();
+    database.insert(&mut transaction_1, 4);
+    println!("Insert 4 in tx 1");
 
-    database.insert(&mut transaction1, 10);
-    println!("Insert 10 by transaction1");
-    database.insert(&mut transaction2, 20);
-    println!("Insert 20 by transaction2");
+    let mut transaction = database.begin();
+    transaction.lock_wait = LockWait::NoWait;
+    println!("Read all in tx 2 with NOWAIT");
+    println!("  values: {:?}", database.try_read_all(&mut transaction));
 
-    database.commit(&mut transaction1);
-    println!("Commit transaction1");
-    println!("Not commit transaction2 and shutdown.\n");
+    let mut transaction = database.begin();
+    transaction.lock_wait = LockWait::Timeout(Duration::from_millis(100));
+    let start = Instant::now();
+    let values = database.try_read_all(&mut transaction);
+    println!("Read all in tx 3 with a 100ms lock timeout");
+    println!(
+        "  values: {:?} after {}ms",
+        values,
+        start.elapsed().as_millis() / 10 * 10
+    );
+
+    let mut transaction = database.begin();
+    transaction.lock_wait = LockWait::SkipLocked;
+    println!("Read all in tx 4 with SKIP LOCKED");
+    println!("  values: {:?}", database.try_read_all(&mut transaction));
+    database.commit(&mut transaction);
+
+    database.commit(&mut transaction_1);
+    println!("Commit tx 1");
+    let mut transaction = database.begin();
+    println!("Read all in tx 5");
+    println!("  values: {:?}", database.read_all(&mut transaction));
+}
+
+fn multi_granularity_locking_example() {
+    let database = Database::init("db", "log", 3);
+    database.set_default_isolation_level(IsolationLevel::RepeatableRead);
+
+    println!("______________________");
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 1);
+    database.insert(&mut transaction, 2);
+    database.commit(&mut transaction);
+    println!("Insert 1, 2 in tx 0 and commit");
+
+    let mut transaction_1 = database.begin();
+    let values = database.read_all(&mut transaction_1);
+    println!("Read all in tx 1, which locks page 0 in S");
+    println!("  values: {:?}", values);
+    let mut transaction = database.begin();
+    transaction.lock_wait = LockWait::NoWait;
+    println!("Insert 3 in tx 2 with NOWAIT, which needs page 0 in IX");
+    println!("  {:?}", database.try_insert(&mut transaction, 3));
+    database.commit(&mut transaction_1);
+    println!("Commit tx 1");
+
+    let mut transaction_3 = database.begin();
+    database
+        .lock_table(&mut transaction_3, LockType::Exclusive)
+        .unwrap();
+    database.insert(&mut transaction_3, 3);
+    database.insert(&mut transaction_3, 4);
+    println!("Lock the table in X in tx 3, insert 3, 4");
+    let mut transaction = database.begin();
+    transaction.lock_wait = LockWait::NoWait;
+    println!("Read all in tx 4 with NOWAIT, which needs the table in IS");
+    println!("  values: {:?}", database.try_read_all(&mut transaction));
+    database.commit(&mut transaction_3);
+    println!("Commit tx 3");
+
+    let mut transaction_5 = database.begin();
+    database
+        .lock_table(&mut transaction_5, LockType::Shared)
+        .unwrap();
+    println!("Lock the table in S in tx 5");
+    let mut transaction = database.begin();
+    transaction.lock_wait = LockWait::NoWait;
+    println!("Read all in tx 6 with NOWAIT, which needs the table in IS");
+    println!("  values: {:?}", database.try_read_all(&mut transaction));
+    database.commit(&mut transaction);
+    let mut transaction = database.begin();
+    transaction.lock_wait = LockWait::NoWait;
+    println!("Insert 5 in tx 7 with NOWAIT, which needs the table in IX");
+    println!("  {:?}", database.try_insert(&mut transaction, 5));
+    database.commit(&mut transaction_5);
+    println!("Commit tx 5");
+}
+
+fn lock_escalation_example() {
+    let database = Database::init("db", "log", 3);
+    database.set_default_isolation_level(IsolationLevel::RepeatableRead);
+    database.set_lock_escalation_threshold(4);
+
+    println!("______________________");
+    let mut transaction_0 = database.begin();
+    for tuple in 1..=4 {
+        database.insert(&mut transaction_0, tuple);
+    }
+    println!("Set the escalation threshold to 4 row locks, insert 1..=4 in tx 0");
+    println!(
+        "  lock table: {} bytes",
+        database.lock_manager.memory_usage()
+    );
+    let mut transaction = database.begin();
+    transaction.lock_wait = LockWait::SkipLocked;
+    println!("Read all in tx 1 with SKIP LOCKED");
+    println!("  values: {:?}", database.try_read_all(&mut transaction));
+    database.commit(&mut transaction);
+
+    database.insert(&mut transaction_0, 5);
+    println!("Insert 5 in tx 0, which escalates to a table lock in X");
+    println!(
+        "  lock table: {} bytes",
+        database.lock_manager.memory_usage()
+    );
+    let mut transaction = database.begin();
+    transaction.lock_wait = LockWait::NoWait;
+    println!("Read all in tx 2 with NOWAIT, which needs the table in IS");
+    println!("  values: {:?}", database.try_read_all(&mut transaction));
+
+    database.commit(&mut transaction_0);
+    println!("Commit tx 0");
+    println!(
+        "  lock table: {} bytes",
+        database.lock_manager.memory_usage()
+    );
+
+    database.set_lock_escalation_threshold(LockManager::DEFAULT_ESCALATION_THRESHOLD);
+    let mut transaction_3 = database.begin();
+    database.insert(&mut transaction_3, 6);
+    let budget = database.lock_manager.memory_usage();
+    database.set_lock_memory_budget(budget);
+    println!(
+        "Reset the threshold, insert 6 in tx 3 and set the lock table budget to {} bytes",
+        budget
+    );
+    database.insert(&mut transaction_3, 7);
+    println!("Insert 7 in tx 3, which goes over the budget and escalates");
+    println!(
+        "  lock table: {} bytes",
+        database.lock_manager.memory_usage()
+    );
+    database.commit(&mut transaction_3);
+    println!("Commit tx 3");
+}
+
+// Returns once the transaction is queued for a lock, so the examples do not
+// depend on how long a thread takes to get there.
+fn wait_until_waiting(database: &Database, transaction_id: u8) {
+    while !database
+        .lock_manager
+        .wait_for_graph
+        .edges()
+        .iter()
+        .any(|(waiter, _)| *waiter == transaction_id)
+    {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn phantom_protection_example() {
+    let database = Arc::new(Database::init("db", "log", 3));
+
+    println!("______________________");
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 1);
+    database.insert(&mut transaction, 2);
+    database.commit(&mut transaction);
+    println!("Insert 1, 2 in tx 0 and commit");
+
+    let mut transaction_1 = database.begin();
+    transaction_1.isolation_level = IsolationLevel::Serializable;
+    let values = database.read_all(&mut transaction_1);
+    println!("Read all in serializable tx 1");
+    println!("  values: {:?}", values);
+
+    let database_clone = database.clone();
+    let (sender, receiver) = mpsc::channel();
+    let writer = thread::spawn(move || {
+        let mut transaction = database_clone.begin();
+        sender.send(transaction.transaction_id).unwrap();
+        database_clone.insert(&mut transaction, 3);
+        database_clone.commit(&mut transaction);
+    });
+    wait_until_waiting(&database, receiver.recv().unwrap());
+    println!("Insert 3 in tx 2, which waits for the table lock of tx 1");
+    let values_again = database.read_all(&mut transaction_1);
+    assert_eq!(values, values_again);
+    println!("Read all again in tx 1");
+    println!("  values: {:?}", values_again);
+    database.commit(&mut transaction_1);
+    writer.join().unwrap();
+    println!("Commit tx 1, then tx 2 inserts 3 and commits");
 
-    let database = Database::load("db", "log", 10);
     let mut transaction = database.begin();
     let values = database.read_all(&mut transaction);
-    println!("Read all");
+    println!("Read all in tx 3");
     println!("  values: {:?}", values);
 }
 
-fn concurrent_isolation_example() {
-    let database = Arc::new(Database::init("db", "log", 10));
+fn lock_view_example() {
+    let database = Arc::new(Database::init("db", "log", 3));
+    database.set_default_isolation_level(IsolationLevel::RepeatableRead);
 
     println!("______________________");
+    let mut transaction_0 = database.begin();
+    database.insert(&mut transaction_0, 1);
+    println!("Insert 1 in tx 0");
     let database_clone = database.clone();
-    thread::spawn(move || {
-        let mut transaction1 = database_clone.begin();
-        println!("Start transaction1");
-        database_clone.insert(&mut transaction1, 10);
-        println!("Insert 10 by transaction1");
-        println!("Sleep 3 seconds(transaction1)");
-        thread::sleep(Duration::from_secs(3));
-        database_clone.commit(&mut transaction1);
-        println!("Commit transaction1");
+    let (sender, receiver) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        let mut transaction = database_clone.begin();
+        sender.send(transaction.transaction_id).unwrap();
+        let values = database_clone.read_all(&mut transaction);
+        database_clone.commit(&mut transaction);
+        values
     });
-    let mut transaction2 = database.begin();
-    println!("Start transaction2");
-    thread::sleep(Duration::from_secs(1));
-    println!("Try to read all by transaction2");
-    let values = database.read_all(&mut transaction2);
-    println!("Read all by transaction2");
+    wait_until_waiting(&database, receiver.recv().unwrap());
+    println!("Read all in tx 1, which waits for tx 0");
+
+    for view in [SystemView::Locks, SystemView::LockWaits] {
+        // wait_time_ms differs from run to run, so it is left out.
+        let columns = view.columns();
+        let shown = columns.len().min(4);
+        println!("{}", columns[..shown].join(" | "));
+        for row in database.read_system_view(view) {
+            println!("  {}", row[..shown].join(" | "));
+        }
+    }
+
+    database.commit(&mut transaction_0);
+    println!("Commit tx 0");
+    println!("  tx 1 values: {:?}", reader.join().unwrap());
+}
+
+fn fair_lock_queue_example() {
+    let database = Arc::new(Database::init("db", "log", 3));
+    database.set_default_isolation_level(IsolationLevel::RepeatableRead);
+
+    println!("______________________");
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 1);
+    database.commit(&mut transaction);
+    println!("Insert 1 in tx 0 and commit");
+    let mut transaction_1 = database.begin();
+    let values = database.read_all(&mut transaction_1);
+    println!("Read all in tx 1");
+    println!("  values: {:?}", values);
+
+    let database_clone = database.clone();
+    let (sender, receiver) = mpsc::channel();
+    let writer = thread::spawn(move || {
+        let mut transaction = database_clone.begin();
+        sender.send(transaction.transaction_id).unwrap();
+        database_clone.insert(&mut transaction, 2);
+        database_clone.commit(&mut transaction);
+    });
+    wait_until_waiting(&database, receiver.recv().unwrap());
+    println!("Insert 2 in tx 2, which waits for tx 1");
+    let database_clone = database.clone();
+    let (sender, receiver) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        let mut transaction = database_clone.begin();
+        sender.send(transaction.transaction_id).unwrap();
+        let values = database_clone.read_all(&mut transaction);
+        database_clone.commit(&mut transaction);
+        values
+    });
+    wait_until_waiting(&database, receiver.recv().unwrap());
+    println!("Read all in tx 3, which queues behind tx 2");
+    println!("{}", SystemView::LockWaits.columns().join(" | "));
+    for row in database.read_system_view(SystemView::LockWaits) {
+        println!("  {}", row.join(" | "));
+    }
+
+    database.commit(&mut transaction_1);
+    writer.join().unwrap();
+    println!("Commit tx 1, then tx 2 inserts 2 and commits");
+    println!("  tx 3 values: {:?}", reader.join().unwrap());
+}
+
+fn snapshot_isolation_example() {
+    let database = Database::init("db", "log", 3);
+
+    println!("______________________");
+    let mut transaction = database.begin();
+    database.insert(&mut transaction, 1);
+    database.insert(&mut transaction, 2);
+    database.commit(&mut transaction);
+    println!("Insert 1, 2 in tx 0 and commit");
+
+    let mut transaction_1 = database.begin();
+    let values = database.read_all(&mut transaction_1);
+    println!("Read all in snapshot tx 1");
+    println!("  values: {:?}", values);
+
+    let mut transaction_2 = database.begin();
+    transaction_2.lock_wait = LockWait::NoWait;
+    println!("Insert 3 in tx 2 with NOWAIT, which tx 1 does not block");
+    println!("  {:?}", database.try_insert(&mut transaction_2, 3));
+    transaction_1.lock_wait = LockWait::NoWait;
+    let values = database.try_read_all(&mut transaction_1);
+    println!("Read all in tx 1 with NOWAIT, which tx 2 does not block");
+    println!("  values: {:?}", values);
+
+    database.commit(&mut transaction_2);
+    println!("Commit tx 2");
+    let values = database.read_all(&mut transaction_1);
+    println!("Read all in tx 1, whose snapshot is from before the commit");
+    println!("  values: {:?}", values);
+    database.commit(&mut transaction_1);
+
+    let mut transaction_3 = database.begin();
+    let values = database.read_all(&mut transaction_3);
+    println!("Read all in snapshot tx 3");
+    println!("  values: {:?}", values);
+
+    let mut transaction_4 = database.begin();
+    println!("Delete 2 in tx 4");
+    println!("  deleted: {}", database.delete(&mut transaction_4, 2));
+    let mut transaction = database.begin();
+    transaction.lock_wait = LockWait::NoWait;
+    println!("Delete 2 in tx 5 with NOWAIT, which needs the row lock of tx 4");
+    println!("  {:?}", database.try_delete(&mut transaction, 2));
+    database.commit(&mut transaction_4);
+    println!("Commit tx 4");
+    let values = database.read_all(&mut transaction_3);
+    println!("Read all in tx 3, whose snapshot is from before the delete");
+    println!("  values: {:?}", values);
+    println!("Delete 2 in tx 3, which tx 4 deleted after the snapshot");
+    println!("  {:?}", database.try_delete(&mut transaction_3, 2));
+
+    let mut transaction = database.begin();
+    println!("Delete 3 in tx 6 and abort");
+    println!("  deleted: {}", database.delete(&mut transaction, 3));
+    database.abort(&mut transaction);
+    let mut transaction = database.begin();
+    let values = database.read_all(&mut transaction);
+    println!("Read all in snapshot tx 7");
+    println!("  values: {:?}", values);
+    let mut transaction = database.begin();
+    transaction.isolation_level = IsolationLevel::RepeatableRead;
+    let values = database.read_all(&mut transaction);
+    println!("Read all in repeatable read tx 8");
     println!("  values: {:?}", values);
 }